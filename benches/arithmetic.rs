@@ -0,0 +1,47 @@
+// A tight arithmetic loop, for comparing changes to the interpreter's hot path.
+// Run with `cargo bench`, or `cargo bench --features fn-dispatch` to compare `match` dispatch with
+// the function-pointer table.
+use std::io;
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{Value, VM};
+
+const SOURCE: &str = "
+var i = 0;
+var sum = 0;
+while (i < 1000000) {
+    sum = sum + i * 2 - 1;
+    i = i + 1;
+}
+sum
+";
+
+// The sum of 2i - 1 for i below n = 1000000 is n * n - 2 * n
+const EXPECTED: Value = Value::Int(1_000_000 * 1_000_000 - 2 * 1_000_000);
+
+#[cfg_attr(not(feature = "fn-dispatch"), allow(unused_variables))]
+fn run(fn_dispatch: bool) {
+    let mut vm = VM::with_output(Box::new(io::sink()));
+    #[cfg(feature = "fn-dispatch")]
+    vm.set_fn_dispatch(fn_dispatch);
+    let result = vm.interpret_value(SOURCE).unwrap();
+
+    // A broken interpreter can be fast for the wrong reasons
+    assert_eq!(result, EXPECTED);
+}
+
+fn arithmetic_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arithmetic loop");
+    group.bench_function("match", |b| b.iter(|| run(false)));
+    #[cfg(feature = "fn-dispatch")]
+    group.bench_function("fn table", |b| b.iter(|| run(true)));
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Each run is a million iterations, so fewer, longer samples are enough
+    config = Criterion::default().sample_size(10).measurement_time(Duration::from_secs(10));
+    targets = arithmetic_loop
+}
+criterion_main!(benches);
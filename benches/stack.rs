@@ -0,0 +1,36 @@
+// Recursive calls, which push and pop a frame's worth of stack slots each, for comparing changes
+// to the VM's value stack.
+// Run with `cargo bench`.
+use std::io;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{Value, VM};
+
+const SOURCE: &str = "
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 2) + fib(n - 1);
+}
+fib(25)
+";
+
+const EXPECTED: Value = Value::Int(75025);
+
+fn recursive_calls(c: &mut Criterion) {
+    c.bench_function("recursive calls", |b| {
+        b.iter(|| {
+            let mut vm = VM::with_output(Box::new(io::sink()));
+            let result = vm.interpret_value(SOURCE).unwrap();
+
+            // A broken interpreter can be fast for the wrong reasons
+            assert_eq!(result, EXPECTED);
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    // Each run makes a quarter of a million calls, so fewer samples are enough
+    config = Criterion::default().sample_size(10);
+    targets = recursive_calls
+}
+criterion_main!(benches);
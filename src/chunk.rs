@@ -1,33 +1,134 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::iter;
+use std::rc::Rc;
 use anyhow::{anyhow, Result};
 use thiserror::Error;
+use crate::value::Value;
 
-#[repr(u8)]
-#[derive(Clone, Copy, Debug)]
-pub enum OpCode {
-    Return = 0,
-    Constant,
-    ConstantLong,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide
+// Defines `OpCode` together with its conversion from a byte and its disassembly name, so a new
+// opcode can never be missing from either
+macro_rules! opcodes {
+    ($($name:ident => $display:literal),* $(,)?) => {
+        #[repr(u8)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum OpCode {
+            $($name),*
+        }
+
+        impl TryFrom<u8> for OpCode {
+            type Error = ();
+
+            fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+                $(
+                    if value == OpCode::$name as u8 {
+                        return Ok(OpCode::$name);
+                    }
+                )*
+
+                Err(())
+            }
+        }
+
+        impl fmt::Display for OpCode {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let name = match self {
+                    $(OpCode::$name => $display),*
+                };
+
+                f.write_str(name)
+            }
+        }
+    };
 }
 
-impl TryFrom<u8> for OpCode {
-    type Error = ();
+// Passes every opcode with its disassembly name to `$callback`, for code generated per opcode
+macro_rules! with_opcodes {
+    ($callback:ident) => {
+        $callback! {
+            Return => "OP_RETURN",
+            Constant => "OP_CONSTANT",
+            ConstantLong => "OP_CONSTANT_LONG",
+            Negate => "OP_NEGATE",
+            Add => "OP_ADD",
+            Subtract => "OP_SUBTRACT",
+            Multiply => "OP_MULTIPLY",
+            Divide => "OP_DIVIDE",
+            Nil => "OP_NIL",
+            True => "OP_TRUE",
+            False => "OP_FALSE",
+            Print => "OP_PRINT",
+            DefineGlobal => "OP_DEFINE_GLOBAL",
+            GetGlobal => "OP_GET_GLOBAL",
+            SetGlobal => "OP_SET_GLOBAL",
+            Pop => "OP_POP",
+            GetLocal => "OP_GET_LOCAL",
+            SetLocal => "OP_SET_LOCAL",
+            JumpIfFalse => "OP_JUMP_IF_FALSE",
+            Jump => "OP_JUMP",
+            Loop => "OP_LOOP",
+            Equal => "OP_EQUAL",
+            Greater => "OP_GREATER",
+            Less => "OP_LESS",
+            Not => "OP_NOT",
+            Call => "OP_CALL",
+            // Followed by the function's constant index, then an (is_local, index) byte pair per upvalue:
+            // a slot of the enclosing function's locals if is_local is 1, otherwise one of its upvalues
+            Closure => "OP_CLOSURE",
+            GetUpvalue => "OP_GET_UPVALUE",
+            SetUpvalue => "OP_SET_UPVALUE",
+            CloseUpvalue => "OP_CLOSE_UPVALUE",
+            Class => "OP_CLASS",
+            GetProperty => "OP_GET_PROPERTY",
+            SetProperty => "OP_SET_PROPERTY",
+            Method => "OP_METHOD",
+            Invoke => "OP_INVOKE",
+            Inherit => "OP_INHERIT",
+            GetSuper => "OP_GET_SUPER",
+            SuperInvoke => "OP_SUPER_INVOKE",
+            Duplicate => "OP_DUPLICATE",
+            PopN => "OP_POP_N",
+            SmallInt => "OP_SMALL_INT",
+            PrintNoNewline => "OP_PRINT_NO_NEWLINE",
+            BuildList => "OP_BUILD_LIST",
+            GetIndex => "OP_GET_INDEX",
+            SetIndex => "OP_SET_INDEX",
+            Constant2 => "OP_CONSTANT_2",
+            Modulo => "OP_MODULO",
+            // Copies the top two values, keeping their order
+            Duplicate2 => "OP_DUPLICATE_2",
+        }
+    };
+}
+#[cfg(feature = "fn-dispatch")]
+pub(crate) use with_opcodes;
+
+with_opcodes!(opcodes);
 
-    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+// Identifies constants that can share a slot in the pool. Stricter than `Value`'s equality: 1 and
+// 1.0 stay separate constants, and floats are compared bitwise so NaN matches itself and 0.0
+// doesn't match -0.0.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    // Strings are interned, so equal strings are the same allocation, which the pool keeps alive
+    Str(*const String),
+}
+
+impl ConstantKey {
+    // Every function constant is a distinct declaration, so only plain values are shared
+    fn new(value: &Value) -> Option<Self> {
         match value {
-            value if value == OpCode::Return as u8 => Ok(OpCode::Return),
-            value if value == OpCode::Constant as u8 => Ok(OpCode::Constant),
-            value if value == OpCode::ConstantLong as u8 => Ok(OpCode::ConstantLong),
-            value if value == OpCode::Negate as u8 => Ok(OpCode::Negate),
-            value if value == OpCode::Add as u8 => Ok(OpCode::Add),
-            value if value == OpCode::Subtract as u8 => Ok(OpCode::Subtract),
-            value if value == OpCode::Multiply as u8 => Ok(OpCode::Multiply),
-            value if value == OpCode::Divide as u8 => Ok(OpCode::Divide),
-            _ => Err(())
+            Value::Nil => Some(ConstantKey::Nil),
+            Value::Bool(value) => Some(ConstantKey::Bool(*value)),
+            Value::Int(value) => Some(ConstantKey::Int(*value)),
+            Value::Number(value) => Some(ConstantKey::Number(value.to_bits())),
+            Value::Str(value) => Some(ConstantKey::Str(Rc::as_ptr(value))),
+            _ => None,
         }
     }
 }
@@ -35,16 +136,51 @@ impl TryFrom<u8> for OpCode {
 pub struct Chunk {
     name: String,
     code: Vec<u8>,
-    constants: Vec<f32>,
+    constants: Vec<Value>,
+    // Index of every shareable constant in the pool
+    constant_indices: HashMap<ConstantKey, usize>,
     line_info: Vec<usize>,
     first_line: usize,
+    max_constants: usize,
 }
 
 #[derive(Error, Debug)]
-pub enum ChunkError {}
+pub enum ChunkError {
+    #[error("Too much code to jump over.")]
+    JumpTooLarge,
+
+    #[error("Loop body too large.")]
+    LoopTooLarge,
+
+    #[error("Too many constants in one chunk.")]
+    TooManyConstants,
+}
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("Unknown opcode {byte} at offset {offset}.")]
+    UnknownOpcode { offset: usize, byte: u8 },
+
+    #[error("Instruction at offset {offset} is missing operands.")]
+    MissingOperands { offset: usize },
+
+    #[error("Constant index {index} at offset {offset} is out of bounds.")]
+    ConstantOutOfBounds { offset: usize, index: usize },
+
+    #[error("Closure at offset {offset} doesn't refer to a function.")]
+    NotAFunction { offset: usize },
+
+    #[error("Jump at offset {offset} targets {target}, which isn't the start of an instruction.")]
+    InvalidJumpTarget { offset: usize, target: usize },
+
+    #[error("Chunk doesn't end with a return.")]
+    MissingReturn,
+}
 
 impl Chunk {
     const INITIAL_CAPACITY: usize = 8;
+    // Every index has to fit in OP_CONSTANT_LONG's 32-bit operand
+    pub const MAX_CONSTANTS: usize = u32::MAX as usize;
 
     fn new_id() -> u32 {
         static mut NEXT_ID: u32 = 0;
@@ -64,8 +200,10 @@ impl Chunk {
             name: name.unwrap_or(Chunk::new_id().to_string().as_str()).to_string(),
             code: Vec::with_capacity(Self::INITIAL_CAPACITY),
             constants: Vec::with_capacity(Self::INITIAL_CAPACITY),
+            constant_indices: HashMap::new(),
             line_info: Vec::new(),
             first_line: 0,
+            max_constants: Self::MAX_CONSTANTS,
         }
     }
 
@@ -75,8 +213,10 @@ impl Chunk {
             self.first_line = line_no;
             self.line_info.push(1);
         } else {
-            // Insert a new row number if needed
-            if line_no > (self.line_info.len() - 1 + self.first_line) {
+            // Insert a new row number if needed, with empty rows for any lines skipped over
+            let last_line = self.line_info.len() - 1 + self.first_line;
+            if line_no > last_line {
+                self.line_info.resize(line_no - self.first_line, 0);
                 self.line_info.push(1);
             } else {
                 self.line_info[line_no - self.first_line] += 1;
@@ -91,15 +231,148 @@ impl Chunk {
     }
 
     pub fn write_constant(&mut self, constant_index: u32, line_no: usize) {
-        self.determine_line_info(line_no);
-
         let constant_bytes = constant_index.to_ne_bytes();
         for byte in constant_bytes {
-            self.code.push(byte);
+            self.write(byte, line_no);
+        }
+    }
+
+    // Picks the narrowest of OP_CONSTANT, OP_CONSTANT_2 and OP_CONSTANT_LONG that fits the index
+    pub fn write_constant_op(&mut self, value: Value, line_no: usize) -> std::result::Result<(), ChunkError> {
+        let constant_index = self.add_constant(value)?;
+
+        if let Ok(short_index) = u8::try_from(constant_index) {
+            self.write(OpCode::Constant as u8, line_no);
+            self.write(short_index, line_no);
+        } else if let Ok(index) = u16::try_from(constant_index) {
+            self.write(OpCode::Constant2 as u8, line_no);
+            for byte in index.to_be_bytes() {
+                self.write(byte, line_no);
+            }
+        } else {
+            self.write(OpCode::ConstantLong as u8, line_no);
+            self.write_constant(constant_index as u32, line_no);
+        }
+
+        Ok(())
+    }
+
+    // Writes a jump with a placeholder operand, returning the operand's offset for `patch_jump`
+    pub fn write_jump(&mut self, opcode: OpCode, line_no: usize) -> usize {
+        self.write(opcode as u8, line_no);
+        self.write(0xff, line_no);
+        self.write(0xff, line_no);
+
+        self.code.len() - 2
+    }
+
+    // Points the jump whose operand is at `offset` to the next instruction to be written
+    pub fn patch_jump(&mut self, offset: usize) -> std::result::Result<(), ChunkError> {
+        // Skip over the jump operand itself
+        let jump = self.code.len() - offset - 2;
+        let jump = u16::try_from(jump).map_err(|_| ChunkError::JumpTooLarge)?;
+
+        self.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+
+    // Writes a backwards jump to `loop_start`
+    pub fn write_loop(&mut self, loop_start: usize, line_no: usize) -> std::result::Result<(), ChunkError> {
+        self.write(OpCode::Loop as u8, line_no);
+
+        // Also jump back over the loop operand itself
+        let offset = self.code.len() - loop_start + 2;
+        let offset = u16::try_from(offset).map_err(|_| ChunkError::LoopTooLarge)?;
+
+        for byte in offset.to_be_bytes() {
+            self.write(byte, line_no);
+        }
+
+        Ok(())
+    }
+
+    pub fn read_short(&self, offset: usize) -> Option<u16> {
+        let operand_bytes = self.code.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes(operand_bytes.try_into().ok()?))
+    }
+
+    pub fn read_constant_long_index(&self, offset: usize) -> Option<usize> {
+        let operand_bytes = self.code.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes(operand_bytes.try_into().ok()?) as usize)
+    }
+
+    // The value pushed by the constant instruction at `offset` and the instruction's length, if
+    // it is one
+    pub fn read_constant_push(&self, offset: usize) -> Option<(Value, usize)> {
+        match OpCode::try_from(*self.code.get(offset)?).ok()? {
+            OpCode::SmallInt => Some((Value::Int(*self.code.get(offset + 1)? as i64), 2)),
+            OpCode::Constant => Some((self.get_constant(*self.code.get(offset + 1)? as usize)?.clone(), 2)),
+            OpCode::Constant2 => Some((self.get_constant(self.read_short(offset + 1)? as usize)?.clone(), 3)),
+            OpCode::ConstantLong => Some((self.get_constant(self.read_constant_long_index(offset + 1)?)?.clone(), 5)),
+            _ => None,
+        }
+    }
+
+    // Drops the code from `len` onwards along with its line info, keeping the constant pool
+    pub fn truncate(&mut self, len: usize) {
+        let mut removed = self.code.len().saturating_sub(len);
+        self.code.truncate(len);
+
+        // The removed bytes are the last ones written, so they're counted on the last lines
+        while removed > 0 {
+            let last = self.line_info.last_mut().unwrap();
+            let taken = removed.min(*last);
+            *last -= taken;
+            removed -= taken;
+
+            if *last == 0 {
+                self.line_info.pop();
+            }
+        }
+        while self.line_info.last() == Some(&0) {
+            self.line_info.pop();
+        }
+        if self.line_info.is_empty() {
+            self.first_line = 0;
         }
     }
 
-    fn get_line(&self, instr_index: usize) -> usize {
+    // Drops the constants from `len` onwards, once the code using them has been truncated
+    pub fn truncate_constants(&mut self, len: usize) {
+        for value in self.constants.drain(len..) {
+            if let Some(key) = ConstantKey::new(&value) {
+                self.constant_indices.remove(&key);
+            }
+        }
+    }
+
+    // First source line with code in this chunk, None while it's empty
+    pub fn first_line(&self) -> Option<usize> {
+        (!self.line_info.is_empty()).then_some(self.first_line)
+    }
+
+    pub fn last_line(&self) -> Option<usize> {
+        (!self.line_info.is_empty()).then(|| self.first_line + self.line_info.len() - 1)
+    }
+
+    // Number of source lines from the first to the last, including lines without code
+    pub fn line_count(&self) -> usize {
+        self.line_info.len()
+    }
+
+    // The line of every instruction, as (offset, line) pairs in offset order. Steps over operands
+    // the same way disassembly does, so they aren't mistaken for instructions.
+    pub fn source_lines(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut offset = 0;
+        iter::from_fn(move || {
+            let start = offset;
+            // Only fails once the offset is past the end of the code
+            offset += self.write_instruction(&mut io::sink(), start).ok()?;
+            Some((start, self.get_line(start)))
+        })
+    }
+
+    pub fn get_line(&self, instr_index: usize) -> usize {
         let mut line_offset: usize = 0;
 
         for (line_index, line_count) in self.line_info.iter().enumerate() {
@@ -115,64 +388,157 @@ impl Chunk {
         0usize // TODO: Better error handling
     }
 
-    #[cfg(debug_assertions)]
     pub fn disassemble_instruction(&self, offset: usize) -> Result<usize> {
+        self.write_instruction(&mut io::stdout().lock(), offset)
+    }
+
+    // Writes the instruction at `offset`, returning its length in bytes
+    pub fn write_instruction(&self, out: &mut dyn Write, offset: usize) -> Result<usize> {
         let instruction = self.code.get(offset).ok_or(
             anyhow!("Chunk {}: Instruction index {} out of bounds, chunk size: {}.",
             self.name, offset, self.code.len()))?;
 
         if let Ok(opcode) = OpCode::try_from(*instruction) {
             return match opcode {
-                OpCode::Return => {
-                    print!("OP_RETURN\n");
-
-                    Ok(1)
-                }
+                OpCode::Return
+                | OpCode::Negate
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::Print
+                | OpCode::Pop
+                | OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::Not
+                | OpCode::CloseUpvalue
+                | OpCode::Inherit
+                | OpCode::Duplicate
+                | OpCode::Duplicate2
+                | OpCode::PrintNoNewline
+                | OpCode::GetIndex
+                | OpCode::SetIndex
+                | OpCode::Modulo => self.simple_instruction(out, opcode),
                 OpCode::Constant => {
                     let constant_index = self.code.get(offset + 1).unwrap();
                     let constant = self.constants.get(*constant_index as usize).unwrap();
-                    print!("OP_CONSTANT {} {}\n", constant_index, constant);
+                    writeln!(out, "{} {} {}", opcode, constant_index, constant)?;
 
                     Ok(2)
                 },
                 OpCode::ConstantLong => {
-                    let constant_index = self.code.get(offset + 1).unwrap();
-                    let constant = self.constants.get(*constant_index as usize).unwrap();
-                    print!("OP_CONSTANT_LONG {} {}\n", constant_index, constant);
+                    let constant_index = self.read_constant_long_index(offset + 1).unwrap();
+                    let constant = self.constants.get(constant_index).unwrap();
+                    writeln!(out, "{} {} {}", opcode, constant_index, constant)?;
 
                     Ok(5)
                 },
-                OpCode::Negate => {
-                    print!("OP_NEGATE\n");
-                    Ok(1)
-                },
-                OpCode::Add => {
-                    print!("OP_ADD\n");
-                    Ok(1)
-                },
-                OpCode::Subtract => {
-                    print!("OP_SUBTRACT\n");
-                    Ok(1)
-                },
-                OpCode::Multiply => {
-                    print!("OP_MULTIPLY\n");
-                    Ok(1)
+                OpCode::Constant2 => {
+                    let constant_index = self.read_short(offset + 1).unwrap() as usize;
+                    let constant = self.constants.get(constant_index).unwrap();
+                    writeln!(out, "{} {} {}", opcode, constant_index, constant)?;
+
+                    Ok(3)
                 },
-                OpCode::Divide => {
-                    print!("OP_DIVIDE\n");
-                    Ok(1)
+                OpCode::DefineGlobal
+                | OpCode::GetGlobal
+                | OpCode::SetGlobal
+                | OpCode::Class
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Method
+                | OpCode::GetSuper => self.constant_instruction(out, opcode, offset),
+                OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::Call
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::PopN
+                | OpCode::SmallInt
+                | OpCode::BuildList => self.byte_instruction(out, opcode, offset),
+                OpCode::JumpIfFalse => self.jump_instruction(out, opcode, offset, false),
+                OpCode::Jump => self.jump_instruction(out, opcode, offset, false),
+                OpCode::Loop => self.jump_instruction(out, opcode, offset, true),
+                OpCode::Closure => {
+                    let constant_index = self.code.get(offset + 1).unwrap();
+                    let constant = self.constants.get(*constant_index as usize).unwrap();
+                    writeln!(out, "{} {} {}", opcode, constant_index, constant)?;
+
+                    let upvalue_count = match constant {
+                        Value::Function(function) => function.upvalue_count,
+                        _ => 0,
+                    };
+
+                    // One line for each (is_local, index) pair following the opcode
+                    for upvalue in 0..upvalue_count {
+                        let pair_offset = offset + 2 + upvalue * 2;
+                        let is_local = self.code.get(pair_offset).unwrap();
+                        let index = self.code.get(pair_offset + 1).unwrap();
+                        let kind = if *is_local == 1 { "local" } else { "upvalue" };
+                        writeln!(out, "{:#08x}    |   {} {}", pair_offset, kind, index)?;
+                    }
+
+                    Ok(2 + upvalue_count * 2)
                 },
+                OpCode::Invoke
+                | OpCode::SuperInvoke => self.invoke_instruction(out, opcode, offset),
             };
         } else {
-            print!("{}\n", *instruction);
+            writeln!(out, "{}", *instruction)?;
         }
 
         Ok(1)
     }
 
-    #[cfg(debug_assertions)]
+    fn simple_instruction(&self, out: &mut dyn Write, opcode: OpCode) -> Result<usize> {
+        writeln!(out, "{}", opcode)?;
+
+        Ok(1)
+    }
+
+    fn constant_instruction(&self, out: &mut dyn Write, opcode: OpCode, offset: usize) -> Result<usize> {
+        let constant_index = self.code.get(offset + 1).unwrap();
+        let constant = self.constants.get(*constant_index as usize).unwrap();
+        writeln!(out, "{} {} '{}'", opcode, constant_index, constant)?;
+
+        Ok(2)
+    }
+
+    fn invoke_instruction(&self, out: &mut dyn Write, opcode: OpCode, offset: usize) -> Result<usize> {
+        let constant_index = self.code.get(offset + 1).unwrap();
+        let arg_count = self.code.get(offset + 2).unwrap();
+        let constant = self.constants.get(*constant_index as usize).unwrap();
+        writeln!(out, "{} ({} args) {} '{}'", opcode, arg_count, constant_index, constant)?;
+
+        Ok(3)
+    }
+
+    fn byte_instruction(&self, out: &mut dyn Write, opcode: OpCode, offset: usize) -> Result<usize> {
+        let slot = self.code.get(offset + 1).unwrap();
+        writeln!(out, "{} {}", opcode, slot)?;
+
+        Ok(2)
+    }
+
+    // Shows where the jump lands, relative to the end of its 3 bytes
+    fn jump_instruction(&self, out: &mut dyn Write, opcode: OpCode, offset: usize, backwards: bool) -> Result<usize> {
+        let jump = self.read_short(offset + 1).unwrap() as usize;
+        let target = if backwards { (offset + 3).wrapping_sub(jump) } else { offset + 3 + jump };
+        writeln!(out, "{} {:#08x} -> {:#08x}", opcode, offset, target)?;
+
+        Ok(3)
+    }
+
     pub fn disassemble(&self) {
-        println!("{}: ", self.name);
+        self.write_disassembly(&mut io::stdout().lock()).unwrap();
+    }
+
+    pub fn write_disassembly(&self, out: &mut dyn Write) -> Result<()> {
+        writeln!(out, "{}: ", self.name)?;
 
         let mut offset = 0;
         let mut prev_line = 0;
@@ -183,24 +549,379 @@ impl Chunk {
                 instr_line.to_string()
             } else { "|".to_string() };
 
-            print!("{:#08x} {:>4} ", offset, line_printed);
+            write!(out, "{:#08x} {:>4} ", offset, line_printed)?;
 
-            let instr_offset = self.disassemble_instruction(offset).unwrap();
+            let instr_offset = self.write_instruction(out, offset)?;
             offset += instr_offset;
             prev_line = instr_line;
         }
+
+        Ok(())
+    }
+
+    // Like `write_disassembly`, followed by the constant pool and the decoded line table
+    pub fn disassemble_full(&self, out: &mut dyn Write) -> Result<()> {
+        self.write_disassembly(out)?;
+
+        writeln!(out, "constants:")?;
+        for (index, constant) in self.constants.iter().enumerate() {
+            writeln!(out, "{:>4} {}", index, constant)?;
+        }
+
+        writeln!(out, "lines (first line {}):", self.first_line)?;
+        let mut offset = 0;
+        for (line_index, byte_count) in self.line_info.iter().enumerate() {
+            // Lines without any code have no bytes to show
+            if *byte_count == 0 {
+                continue;
+            }
+
+            writeln!(out, "{:>4} {:#08x}..{:#08x} ({} bytes)",
+                     line_index + self.first_line, offset, offset + byte_count, byte_count)?;
+            offset += byte_count;
+        }
+
+        Ok(())
     }
 
-    pub fn add_constant(&mut self, value: f32) -> u8 {
+    // Reuses the index of an identical constant already in the pool
+    pub fn add_constant(&mut self, value: Value) -> std::result::Result<usize, ChunkError> {
+        let key = ConstantKey::new(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return Ok(*index);
+        }
+
+        if self.constants.len() >= self.max_constants {
+            return Err(ChunkError::TooManyConstants);
+        }
+
+        let index = self.constants.len();
         self.constants.push(value);
-        u8::try_from(self.constants.len() - 1).unwrap() // SAFETY: UNSAFE AF. Sorry.
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+
+        Ok(index)
+    }
+
+    // Lowers the constant pool's capacity, clamped to `MAX_CONSTANTS`
+    pub fn set_max_constants(&mut self, max_constants: usize) {
+        self.max_constants = max_constants.min(Self::MAX_CONSTANTS);
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    // Labels disassembly: the function's name, or "<script>", for compiled chunks
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
     }
 
     pub fn get_code(&self, index: usize) -> Option<&'_ u8> {
         self.code.get(index)
     }
 
-    pub fn get_constant(&self, index: usize) -> Option<&'_ f32> {
+    pub fn get_constant(&self, index: usize) -> Option<&'_ Value> {
         self.constants.get(index)
     }
-}
\ No newline at end of file
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    // Checks that every instruction is complete and only refers to constants and jump targets
+    // that exist, so the chunk can run without reading out of bounds
+    pub fn verify(&self) -> std::result::Result<(), VerifyError> {
+        let mut boundaries = vec![false; self.code.len()];
+        // (offset, target) of every jump, checked once all boundaries are known
+        let mut jumps = Vec::new();
+        let mut last_opcode = None;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            boundaries[offset] = true;
+
+            let byte = self.code[offset];
+            let opcode = OpCode::try_from(byte).map_err(|_| VerifyError::UnknownOpcode { offset, byte })?;
+            let operand = |index: usize| {
+                self.code.get(offset + index).copied().ok_or(VerifyError::MissingOperands { offset })
+            };
+            let constant = |index: usize| {
+                self.constants.get(index).ok_or(VerifyError::ConstantOutOfBounds { offset, index })
+            };
+
+            let length = match opcode {
+                OpCode::Return
+                | OpCode::Negate
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::Print
+                | OpCode::Pop
+                | OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::Not
+                | OpCode::CloseUpvalue
+                | OpCode::Inherit
+                | OpCode::Duplicate
+                | OpCode::Duplicate2
+                | OpCode::PrintNoNewline
+                | OpCode::GetIndex
+                | OpCode::SetIndex
+                | OpCode::Modulo => 1,
+                OpCode::Constant
+                | OpCode::DefineGlobal
+                | OpCode::GetGlobal
+                | OpCode::SetGlobal
+                | OpCode::Class
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Method
+                | OpCode::GetSuper => {
+                    constant(operand(1)? as usize)?;
+                    2
+                }
+                OpCode::ConstantLong => {
+                    let index = self.read_constant_long_index(offset + 1)
+                        .ok_or(VerifyError::MissingOperands { offset })?;
+                    constant(index)?;
+                    5
+                }
+                OpCode::Constant2 => {
+                    let index = self.read_short(offset + 1)
+                        .ok_or(VerifyError::MissingOperands { offset })?;
+                    constant(index as usize)?;
+                    3
+                }
+                OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::Call
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::PopN
+                | OpCode::SmallInt
+                | OpCode::BuildList => {
+                    operand(1)?;
+                    2
+                }
+                OpCode::Invoke | OpCode::SuperInvoke => {
+                    constant(operand(1)? as usize)?;
+                    operand(2)?;
+                    3
+                }
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    let jump = self.read_short(offset + 1).ok_or(VerifyError::MissingOperands { offset })? as usize;
+                    let target = if opcode == OpCode::Loop {
+                        (offset + 3).checked_sub(jump)
+                    } else {
+                        Some(offset + 3 + jump)
+                    };
+
+                    // An underflowing loop can't land anywhere valid
+                    jumps.push((offset, target.unwrap_or(usize::MAX)));
+                    3
+                }
+                OpCode::Closure => {
+                    let Value::Function(function) = constant(operand(1)? as usize)? else {
+                        return Err(VerifyError::NotAFunction { offset });
+                    };
+
+                    // An (is_local, index) pair follows for each upvalue
+                    let length = 2 + function.upvalue_count * 2;
+                    operand(length - 1)?;
+                    length
+                }
+            };
+
+            last_opcode = Some(opcode);
+            offset += length;
+        }
+
+        for (offset, target) in jumps {
+            if !boundaries.get(target).copied().unwrap_or(false) {
+                return Err(VerifyError::InvalidJumpTarget { offset, target });
+            }
+        }
+
+        if last_opcode != Some(OpCode::Return) {
+            return Err(VerifyError::MissingReturn);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_constants_share_a_slot() {
+        let mut chunk = Chunk::new(None);
+
+        let indices: Vec<_> = (0..3).map(|_| chunk.add_constant(Value::Number(1.5)).unwrap()).collect();
+        assert_eq!(indices, [0, 0, 0]);
+        assert_eq!(chunk.constants().len(), 1);
+
+        // Only bit-identical numbers of the same kind are merged
+        chunk.add_constant(Value::Int(1)).unwrap();
+        chunk.add_constant(Value::Number(1.0)).unwrap();
+        chunk.add_constant(Value::Number(-0.0)).unwrap();
+        chunk.add_constant(Value::Number(0.0)).unwrap();
+        assert_eq!(chunk.add_constant(Value::Number(f64::NAN)).unwrap(), 5);
+        assert_eq!(chunk.add_constant(Value::Number(f64::NAN)).unwrap(), 5);
+        assert_eq!(chunk.constants().len(), 6);
+    }
+
+    #[test]
+    fn full_disassembly_lists_constants_and_lines() {
+        let mut chunk = Chunk::new(Some("test"));
+        chunk.write_constant_op(Value::Number(1.5), 3).unwrap();
+        chunk.write(OpCode::Return as u8, 4);
+
+        let mut out = Vec::new();
+        chunk.disassemble_full(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("constants:\n   0 1.5\n"));
+        assert!(out.contains("lines (first line 3):\n   3 0x000000..0x000002 (2 bytes)\n   4 0x000002..0x000003 (1 bytes)\n"));
+    }
+
+    #[test]
+    fn opcodes_round_trip_through_bytes() {
+        let opcodes: Vec<OpCode> = (0..=u8::MAX).filter_map(|byte| OpCode::try_from(byte).ok()).collect();
+
+        // Opcodes are numbered from zero without gaps
+        assert!(opcodes.len() > 1);
+        for (byte, opcode) in opcodes.iter().enumerate() {
+            assert_eq!(*opcode as u8 as usize, byte);
+            assert_eq!(OpCode::try_from(*opcode as u8), Ok(*opcode));
+        }
+    }
+
+    #[test]
+    fn len_counts_bytes_written() {
+        let mut chunk = Chunk::new(None);
+        assert!(chunk.is_empty());
+
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Print as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert_eq!(chunk.len(), 3);
+        assert!(!chunk.is_empty());
+    }
+
+    #[test]
+    fn verify_accepts_a_well_formed_chunk() {
+        let mut chunk = Chunk::new(None);
+        chunk.write_constant_op(Value::Int(300), 1).unwrap();
+        let jump = chunk.write_jump(OpCode::JumpIfFalse, 1);
+        chunk.write(OpCode::Print as u8, 1);
+        chunk.patch_jump(jump).unwrap();
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert!(chunk.verify().is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_an_out_of_range_constant() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(3, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert!(matches!(chunk.verify(), Err(VerifyError::ConstantOutOfBounds { offset: 0, index: 3 })));
+    }
+
+    #[test]
+    fn verify_rejects_unknown_opcodes_and_missing_operands() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(u8::MAX, 1);
+        assert!(matches!(chunk.verify(), Err(VerifyError::UnknownOpcode { offset: 1, byte: u8::MAX })));
+
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Return as u8, 1);
+        chunk.write(OpCode::Jump as u8, 1);
+        chunk.write(0, 1);
+        assert!(matches!(chunk.verify(), Err(VerifyError::MissingOperands { offset: 1 })));
+    }
+
+    #[test]
+    fn adding_past_the_constant_limit_is_an_error() {
+        let mut chunk = Chunk::new(None);
+        chunk.set_max_constants(2);
+
+        chunk.write_constant_op(Value::Int(1000), 1).unwrap();
+        chunk.write_constant_op(Value::Int(1001), 1).unwrap();
+        assert!(matches!(chunk.write_constant_op(Value::Int(1002), 1), Err(ChunkError::TooManyConstants)));
+
+        // A constant already in the pool still fits
+        assert!(chunk.write_constant_op(Value::Int(1000), 1).is_ok());
+        assert_eq!(chunk.constants().len(), 2);
+    }
+
+    #[test]
+    fn source_lines_pair_every_instruction_with_its_line() {
+        let mut chunk = Chunk::new(None);
+        chunk.write_constant_op(Value::Number(1.5), 2).unwrap();
+        chunk.write(OpCode::Print as u8, 2);
+        // Line 3 has no code
+        chunk.write(OpCode::Nil as u8, 4);
+        chunk.write(OpCode::Return as u8, 4);
+
+        assert_eq!(chunk.source_lines().collect::<Vec<_>>(), [(0, 2), (2, 2), (3, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn constants_past_the_first_256_use_a_two_byte_index() {
+        let mut chunk = Chunk::new(None);
+        for value in 0..300 {
+            chunk.write_constant_op(Value::Int(1000 + value), 1).unwrap();
+        }
+
+        // 256 two-byte OP_CONSTANTs, then three-byte OP_CONSTANT_2s
+        let offset = 256 * 2 + 43 * 3;
+        assert_eq!(chunk.code()[offset], OpCode::Constant2 as u8);
+        assert_eq!(chunk.read_constant_push(offset), Some((Value::Int(1299), 3)));
+        assert_eq!(chunk.len(), offset + 3);
+
+        let mut out = Vec::new();
+        chunk.write_instruction(&mut out, offset).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "OP_CONSTANT_2 299 1299\n");
+    }
+
+    #[test]
+    fn opcodes_display_as_their_disassembly_name() {
+        assert_eq!(format!("{}", OpCode::Add), "OP_ADD");
+        assert_eq!(OpCode::JumpIfFalse.to_string(), "OP_JUMP_IF_FALSE");
+    }
+
+    #[test]
+    fn chunk_name_can_be_read_back_and_changed() {
+        let mut chunk = Chunk::new(Some("test"));
+        assert_eq!(chunk.name(), "test");
+
+        chunk.set_name("renamed");
+        assert_eq!(chunk.name(), "renamed");
+    }
+}
@@ -1,10 +1,106 @@
 use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
 use thiserror::Error;
-use crate::chunk::Chunk;
-use crate::scanner::{Scanner, ScannerError, Token};
+use crate::chunk::{Chunk, OpCode};
+use crate::diagnostics;
+use crate::interner::Interner;
+use crate::object::Function;
+use crate::scanner::{KeywordKind, Scanner, ScannerError, Token};
+use crate::value::Value;
 
 pub struct Compiler<'a> {
+    source: &'a str,
     scanner: RefCell<Scanner<'a>>,
+    current: Token,
+    previous: Token,
+    current_line: usize,
+    previous_line: usize,
+    // One entry per function being compiled, the innermost one last
+    states: Vec<FunctionState<'a>>,
+    // One entry per class being compiled, used to reject `this` and `super` outside of methods
+    classes: Vec<ClassState>,
+    // Shared with the VM, so strings from constants and runtime compare equal by pointer
+    strings: Rc<RefCell<Interner>>,
+    // Every error reported so far, compilation carries on after each one to find the rest
+    errors: Vec<CompileError>,
+    // Offset in the current chunk where the left operand of the infix rule being run starts
+    operand_start: usize,
+    // Size of the current chunk's constant pool when that operand started
+    operand_constants: usize,
+    // Whether `print` ends its output with a newline
+    print_newline: bool,
+    // Whether a final top-level expression without a `;` is the script's result, as the REPL and
+    // embedders expect
+    result_expression: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+struct FunctionState<'a> {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Innermost loop last
+    loops: Vec<LoopState>,
+    // Set once the local limit has been reported, so it isn't reported again for every later local
+    too_many_locals: bool,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(function_type: FunctionType, name: Option<&str>) -> Self {
+        // The first slot holds the function being called, or the receiver for methods
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+
+        Self {
+            function: Function::new(name),
+            function_type,
+            locals: vec![Local { name: slot_zero, depth: Some(0), is_captured: false }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            too_many_locals: false,
+        }
+    }
+}
+
+struct Local<'a> {
+    name: &'a str,
+    // None until the initializer has been compiled, so `var a = a;` can be rejected
+    depth: Option<usize>,
+    // Captured locals are moved off the stack when they go out of scope
+    is_captured: bool,
+}
+
+struct LoopState {
+    // Where `continue` jumps back to: the condition, or the increment of a for loop
+    start: usize,
+    // Scope depth outside the loop body, locals deeper than this are discarded when leaving it
+    scope_depth: usize,
+    // Operands of the jumps emitted by `break`, patched to the loop's exit
+    break_jumps: Vec<usize>,
+}
+
+struct Upvalue {
+    // Slot of the captured local if `is_local`, otherwise an index into the enclosing
+    // function's own upvalues
+    index: u8,
+    is_local: bool,
 }
 
 #[derive(Error, Debug)]
@@ -12,21 +108,1726 @@ pub enum CompileError {
     #[error("Failed to compile: {0}")]
     CompilationError(String),
 
-    #[error("Scanner error")]
+    // An error at a specific token, `message` already names the line and token
+    #[error("Failed to compile: {message}")]
+    SyntaxError { message: String, line: usize, column: usize },
+
+    #[error("Scanner error: {0}")]
     ScannerError(#[from] ScannerError),
 }
 
+impl CompileError {
+    // The (line, column) the error points at, when known
+    pub fn location(&self) -> Option<(usize, usize)> {
+        match self {
+            CompileError::SyntaxError { line, column, .. } => Some((*line, *column)),
+            CompileError::ScannerError(err) => Some(err.location()),
+            CompileError::CompilationError(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Conditional,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool) -> Result<(), CompileError>;
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(prefix: Option<ParseFn<'a>>, infix: Option<ParseFn<'a>>, precedence: Precedence) -> Self {
+        Self { prefix, infix, precedence }
+    }
+}
+
 impl<'a, 'outlives_a: 'a> Compiler<'a> {
-    pub fn new(source: &'outlives_a str) -> Self {
+    pub fn new(source: &'outlives_a str, strings: Rc<RefCell<Interner>>) -> Self {
+        Self::from_scanner(Scanner::new(source), strings)
+    }
+
+    // Compiles with an already configured scanner, which keeps its settings across `compile` calls
+    pub fn from_scanner(scanner: Scanner<'a>, strings: Rc<RefCell<Interner>>) -> Self {
         Self {
-            scanner: RefCell::new(Scanner::new(source))
+            source: scanner.source(),
+            scanner: RefCell::new(scanner),
+            current: Token::EOF(0),
+            previous: Token::EOF(0),
+            current_line: 1,
+            previous_line: 1,
+            states: Vec::new(),
+            classes: Vec::new(),
+            strings,
+            errors: Vec::new(),
+            operand_start: 0,
+            operand_constants: 0,
+            print_newline: true,
+            result_expression: true,
+        }
+    }
+
+    pub fn set_print_newline(&mut self, print_newline: bool) {
+        self.print_newline = print_newline;
+    }
+
+    // Script files turn this off, so a missing `;` at their end is still an error
+    pub fn set_result_expression(&mut self, result_expression: bool) {
+        self.result_expression = result_expression;
+    }
+
+    // Compiles the source from the start, so a failed compilation can be retried
+    pub fn compile(&mut self) -> Result<Function, Vec<CompileError>> {
+        self.scanner.borrow_mut().reset(self.source);
+        self.current_line = 1;
+        self.previous_line = 1;
+
+        // A previous compilation may have stopped halfway through a function or class
+        self.states.clear();
+        self.classes.clear();
+        self.errors.clear();
+
+        self.states.push(FunctionState::new(FunctionType::Script, None));
+        self.advance();
+
+        while !self.match_token(Token::EOF(0)) {
+            self.recovering_declaration(true);
+        }
+
+        self.emit_return();
+
+        let function = self.states.pop().unwrap().function;
+        if !self.errors.is_empty() {
+            return Err(mem::take(&mut self.errors));
+        }
+
+        Ok(function)
+    }
+
+    // Compiles a standalone script with its own interner, for tools that only need the bytecode
+    pub fn compile_to_chunk(source: &str) -> Result<Chunk, Vec<CompileError>> {
+        let mut compiler = Compiler::new(source, Rc::new(RefCell::new(Interner::new())));
+        compiler.compile().map(|function| function.chunk)
+    }
+
+    fn state(&self) -> &FunctionState<'a> {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current;
+        self.previous_line = self.current_line;
+
+        // Bad characters are recorded and skipped, so parsing only ever sees valid tokens
+        let mut scanner = self.scanner.borrow_mut();
+        loop {
+            match scanner.scan_token() {
+                Ok(token) => {
+                    self.current = token;
+                    break;
+                }
+                Err(err) => self.errors.push(err.into()),
+            }
+        }
+        self.current_line = scanner.token_line();
+    }
+
+    // Only compares the token kinds, positions are ignored
+    fn check(&self, kind: Token) -> bool {
+        mem::discriminant(&self.current) == mem::discriminant(&kind)
+    }
+
+    fn check_keyword(&self, kind: KeywordKind) -> bool {
+        matches!(self.current, Token::Keyword(_, keyword) if keyword == kind)
+    }
+
+    fn match_token(&mut self, kind: Token) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn match_keyword(&mut self, kind: KeywordKind) -> bool {
+        if !self.check_keyword(kind) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, kind: Token, message: &str) -> Result<(), CompileError> {
+        if self.check(kind) {
+            self.advance();
+            return Ok(());
+        }
+
+        Err(self.error_at(self.current, self.current_line, message))
+    }
+
+    // Consumes the name being declared, where `kind` is what it names, e.g. "variable"
+    fn consume_name(&mut self, kind: &str) -> Result<(), CompileError> {
+        // Carry on with the keyword as the name, so the rest of the declaration is still checked
+        if let Token::Keyword(_, keyword) = self.current {
+            let message = format!("'{}' is a reserved keyword and can't be used as a {} name.", keyword.as_str(), kind);
+            let err = self.error_at(self.current, self.current_line, &message);
+            self.errors.push(err);
+            self.advance();
+
+            return Ok(());
+        }
+
+        self.consume(Token::Identifier(0, 0), &format!("Expect {} name.", kind))
+    }
+
+    fn error_at(&self, token: Token, line: usize, message: &str) -> CompileError {
+        let location = match token {
+            Token::EOF(_) => " at end".to_string(),
+            _ => format!(" at '{}'", self.lexeme(token)),
+        };
+
+        CompileError::SyntaxError {
+            message: format!("[line {}] Error{}: {}", line, location, message),
+            line,
+            column: diagnostics::column_at(self.source, token.start()),
+        }
+    }
+
+    fn lexeme(&self, token: Token) -> &'a str {
+        token.lexeme(self.source)
+    }
+
+    #[inline]
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous_line;
+        self.current_chunk().write(byte, line);
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), CompileError> {
+        let line = self.previous_line;
+        self.current_chunk().write_constant_op(value, line)
+            .map_err(|err| self.error_at(self.previous, self.previous_line, &err.to_string()))
+    }
+
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        let line = self.previous_line;
+        self.current_chunk().write_jump(opcode, line)
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<(), CompileError> {
+        self.current_chunk().patch_jump(offset)
+            .map_err(|err| self.error_at(self.previous, self.previous_line, &err.to_string()))
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), CompileError> {
+        let line = self.previous_line;
+        self.current_chunk().write_loop(loop_start, line)
+            .map_err(|err| self.error_at(self.previous, self.previous_line, &err.to_string()))
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> Result<u8, CompileError> {
+        let name_value = Value::Str(self.strings.borrow_mut().intern(name));
+        let constant_index = self.current_chunk().add_constant(name_value)
+            .map_err(|err| self.error_at(self.previous, self.previous_line, &err.to_string()))?;
+
+        u8::try_from(constant_index)
+            .map_err(|_| self.error_at(self.previous, self.previous_line, "Too many constants in one chunk."))
+    }
+
+    fn get_rule(token: Token) -> ParseRule<'a> {
+        match token {
+            Token::LeftParen(_) => ParseRule::new(Some(Self::grouping), Some(Self::call), Precedence::Call),
+            Token::Dot(_) => ParseRule::new(None, Some(Self::dot), Precedence::Call),
+            Token::LeftBracket(_) => ParseRule::new(Some(Self::list), Some(Self::subscript), Precedence::Call),
+            Token::Question(_) => ParseRule::new(None, Some(Self::conditional), Precedence::Conditional),
+            Token::Minus(_) => ParseRule::new(Some(Self::unary), Some(Self::binary), Precedence::Term),
+            Token::Plus(_) => ParseRule::new(None, Some(Self::binary), Precedence::Term),
+            Token::Slash(_) => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
+            Token::Star(_) => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
+            Token::Percent(_) => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
+            Token::Bang(_) => ParseRule::new(Some(Self::unary), None, Precedence::None),
+            Token::BangEqual(_) | Token::EqualEqual(_) => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Equality)
+            }
+            Token::Greater(_) | Token::GreaterEqual(_) | Token::Less(_) | Token::LessEqual(_) => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
+            }
+            Token::Identifier(_, _) => ParseRule::new(Some(Self::variable), None, Precedence::None),
+            Token::Keyword(_, KeywordKind::And) => ParseRule::new(None, Some(Self::and), Precedence::And),
+            Token::Keyword(_, KeywordKind::Or) => ParseRule::new(None, Some(Self::or), Precedence::Or),
+            Token::Keyword(_, KeywordKind::This) => ParseRule::new(Some(Self::this), None, Precedence::None),
+            Token::Keyword(_, KeywordKind::Super) => ParseRule::new(Some(Self::super_), None, Precedence::None),
+            Token::Number(_, _) => ParseRule::new(Some(Self::number), None, Precedence::None),
+            Token::String(_, _) => ParseRule::new(Some(Self::string), None, Precedence::None),
+            Token::Keyword(_, KeywordKind::True | KeywordKind::False | KeywordKind::Nil) => {
+                ParseRule::new(Some(Self::literal), None, Precedence::None)
+            }
+            _ => ParseRule::new(None, None, Precedence::None),
         }
     }
 
-    pub fn compile(&self, source: &'a str, chunk: &'a mut Chunk) -> Result<(), CompileError> {
-        let mut line = 1usize;
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), CompileError> {
+        self.advance();
+
+        let prefix_rule = Self::get_rule(self.previous).prefix
+            .ok_or_else(|| self.error_at(self.previous, self.previous_line, "Expect expression."))?;
+
+        // Only the lowest precedence expressions may be assigned to, so `a * b = c` is rejected
+        let can_assign = precedence <= Precedence::Assignment;
+        let start = self.current_chunk().len();
+        let constants_start = self.current_chunk().constants().len();
+        prefix_rule(self, can_assign)?;
+
+        while precedence <= Self::get_rule(self.current).precedence {
+            self.advance();
+
+            // Every token with a precedence has an infix rule
+            let infix_rule = Self::get_rule(self.previous).infix.unwrap();
+            self.operand_start = start;
+            self.operand_constants = constants_start;
+            infix_rule(self, can_assign)?;
+        }
+
+        if can_assign && (self.match_token(Token::Equal(0)) || self.match_compound_assignment(can_assign).is_some()) {
+            return Err(self.error_at(self.previous, self.previous_line, "Invalid assignment target."));
+        }
+
+        Ok(())
+    }
+
+    // Compiles a declaration, and on error records it and skips to the next statement so the
+    // rest of the source is still checked
+    fn recovering_declaration(&mut self, top_level: bool) {
+        // The error may have been raised inside nested functions, classes, scopes or loops
+        let states_len = self.states.len();
+        let classes_len = self.classes.len();
+        let state = self.state();
+        let (scope_depth, locals_len, loops_len) = (state.scope_depth, state.locals.len(), state.loops.len());
+
+        let errors_len = self.errors.len();
+        if let Err(err) = self.declaration(top_level) {
+            // A bad token was already reported and skipped, so what the parser trips over next is
+            // only a consequence of it
+            let scanner_failed = self.errors[errors_len..].iter()
+                .any(|err| matches!(err, CompileError::ScannerError(_)));
+            if !scanner_failed {
+                self.errors.push(err);
+            }
+
+            self.states.truncate(states_len);
+            self.classes.truncate(classes_len);
+            let state = self.state_mut();
+            state.scope_depth = scope_depth;
+            state.locals.truncate(locals_len);
+            state.loops.truncate(loops_len);
+
+            self.synchronize();
+        }
+    }
+
+    fn synchronize(&mut self) {
+        while !self.check(Token::EOF(0)) {
+            if matches!(self.previous, Token::Semicolon(_)) {
+                return;
+            }
+
+            if let Token::Keyword(
+                _,
+                KeywordKind::Class
+                | KeywordKind::Fun
+                | KeywordKind::Var
+                | KeywordKind::For
+                | KeywordKind::If
+                | KeywordKind::While
+                | KeywordKind::Print
+                | KeywordKind::Return,
+            ) = self.current
+            {
+                return;
+            }
+
+            self.advance();
+        }
+    }
+
+    // `top_level` is set for declarations directly in the script, outside of any block or statement
+    fn declaration(&mut self, top_level: bool) -> Result<(), CompileError> {
+        if self.match_keyword(KeywordKind::Class) {
+            self.class_declaration()
+        } else if self.match_keyword(KeywordKind::Fun) {
+            self.fun_declaration()
+        } else if self.match_keyword(KeywordKind::Var) {
+            self.var_declaration()
+        } else {
+            self.statement(top_level)
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        let depth = self.state().scope_depth;
+        self.discard_locals(depth);
+
+        let state = self.state_mut();
+        while state.locals.last().is_some_and(|local| local.depth > Some(depth)) {
+            state.locals.pop();
+        }
+    }
+
+    fn block(&mut self) -> Result<(), CompileError> {
+        while !self.check(Token::RightBrace(0)) && !self.check(Token::EOF(0)) {
+            self.recovering_declaration(false);
+        }
+
+        self.consume(Token::RightBrace(0), "Expect '}' after block.")
+    }
+
+    fn class_declaration(&mut self) -> Result<(), CompileError> {
+        self.consume_name("class")?;
+        let class_name = self.lexeme(self.previous);
+        let name_constant = self.identifier_constant(class_name)?;
+        if self.state().scope_depth > 0 {
+            self.declare_variable()?;
+        }
+
+        self.emit_byte(OpCode::Class as u8);
+        self.emit_byte(name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState { has_superclass: false });
+
+        if self.match_token(Token::Less(0)) {
+            self.consume(Token::Identifier(0, 0), "Expect superclass name.")?;
+            self.variable(false)?;
+
+            if self.lexeme(self.previous) == class_name {
+                return Err(self.error_at(self.previous, self.previous_line, "A class can't inherit from itself."));
+            }
+
+            // Every class has its own scope holding `super`, so methods capture the right superclass
+            self.begin_scope();
+            self.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(class_name, false)?;
+            self.emit_byte(OpCode::Inherit as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Keep the class on the stack while its methods are attached to it
+        self.named_variable(class_name, false)?;
+        self.consume(Token::LeftBrace(0), "Expect '{' before class body.")?;
+        while !self.check(Token::RightBrace(0)) && !self.check(Token::EOF(0)) {
+            self.method()?;
+        }
+        self.consume(Token::RightBrace(0), "Expect '}' after class body.")?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<(), CompileError> {
+        self.consume_name("method")?;
+        let name_constant = self.identifier_constant(self.lexeme(self.previous))?;
+
+        let function_type = if self.lexeme(self.previous) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type)?;
+
+        self.emit_byte(OpCode::Method as u8);
+        self.emit_byte(name_constant);
+
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) -> Result<(), CompileError> {
+        let global = self.parse_variable("function")?;
+
+        // Functions may refer to themselves, so they're initialized before their body is compiled
+        self.mark_initialized();
+        self.function(FunctionType::Function)?;
+        self.define_variable(global);
+
+        Ok(())
+    }
+
+    fn function(&mut self, function_type: FunctionType) -> Result<(), CompileError> {
+        let name = self.lexeme(self.previous);
+        self.states.push(FunctionState::new(function_type, Some(name)));
+        self.begin_scope();
+
+        self.consume(Token::LeftParen(0), "Expect '(' after function name.")?;
+        if !self.check(Token::RightParen(0)) {
+            loop {
+                if self.state().function.arity == u8::MAX as usize {
+                    let message = "Can't have more than 255 parameters.";
+                    return Err(self.error_at(self.current, self.current_line, message));
+                }
+                self.state_mut().function.arity += 1;
+
+                let constant = self.parse_variable("parameter")?;
+                self.define_variable(constant);
+
+                if !self.match_token(Token::Comma(0)) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightParen(0), "Expect ')' after parameters.")?;
+        self.consume(Token::LeftBrace(0), "Expect '{' before function body.")?;
+        self.block()?;
+
+        // No need to end the scope, the function's locals are discarded along with its frame
+        self.emit_return();
+        let state = self.states.pop().unwrap();
+
+        let constant_index = self.current_chunk().add_constant(Value::Function(Rc::new(state.function)))
+            .map_err(|err| self.error_at(self.previous, self.previous_line, &err.to_string()))?;
+        let constant_index = u8::try_from(constant_index)
+            .map_err(|_| self.error_at(self.previous, self.previous_line, "Too many constants in one chunk."))?;
+
+        // Each captured variable is described by a pair of bytes following the function constant
+        self.emit_byte(OpCode::Closure as u8);
+        self.emit_byte(constant_index);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+
+        Ok(())
+    }
+
+    fn emit_return(&mut self) {
+        // Initializers always return the new instance
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_byte(OpCode::GetLocal as u8);
+            self.emit_byte(0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.emit_byte(OpCode::Return as u8);
+    }
+
+    fn var_declaration(&mut self) -> Result<(), CompileError> {
+        let global = self.parse_variable("variable")?;
+
+        // Variables declared without an initializer start out as nil
+        if self.match_token(Token::Equal(0)) {
+            self.expression()?;
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(Token::Semicolon(0), "Expect ';' after variable declaration.")?;
+
+        self.define_variable(global);
+
+        Ok(())
+    }
+
+    fn parse_variable(&mut self, kind: &str) -> Result<u8, CompileError> {
+        self.consume_name(kind)?;
+
+        // Locals live on the stack and aren't looked up by name at runtime
+        if self.state().scope_depth > 0 {
+            self.declare_variable()?;
+            return Ok(0);
+        }
+
+        self.identifier_constant(self.lexeme(self.previous))
+    }
+
+    fn declare_variable(&mut self) -> Result<(), CompileError> {
+        let name = self.lexeme(self.previous);
+        let state = self.state();
+
+        for local in state.locals.iter().rev() {
+            if local.depth.is_some_and(|depth| depth < state.scope_depth) {
+                break;
+            }
+
+            if local.name == name {
+                let message = "Already a variable with this name in this scope.";
+                return Err(self.error_at(self.previous, self.previous_line, message));
+            }
+        }
+
+        self.add_local(name);
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: &'a str) {
+        // The local is still added so the rest of the function compiles as written, but its
+        // bytecode is never run since compilation has failed
+        if self.state().locals.len() > u8::MAX as usize && !self.state().too_many_locals {
+            let err = self.error_at(self.previous, self.previous_line, "Too many local variables in function.");
+            self.errors.push(err);
+            self.state_mut().too_many_locals = true;
+        }
+
+        self.state_mut().locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    fn resolve_local(&self, state_index: usize, name: &str) -> Result<Option<u8>, CompileError> {
+        for (slot, local) in self.states[state_index].locals.iter().enumerate().rev() {
+            if local.name == name {
+                if local.depth.is_none() {
+                    let message = "Can't read local variable in its own initializer.";
+                    return Err(self.error_at(self.previous, self.previous_line, message));
+                }
+
+                // Slots past u8::MAX only exist once compilation has already failed
+                return Ok(Some(slot as u8));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Looks the variable up in the enclosing functions, capturing it along the way
+    fn resolve_upvalue(&mut self, state_index: usize, name: &str) -> Result<Option<u8>, CompileError> {
+        if state_index == 0 {
+            return Ok(None);
+        }
+
+        let enclosing = state_index - 1;
+        if let Some(slot) = self.resolve_local(enclosing, name)? {
+            self.states[enclosing].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(state_index, slot, true).map(Some);
+        }
+
+        match self.resolve_upvalue(enclosing, name)? {
+            Some(index) => self.add_upvalue(state_index, index, false).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(&mut self, state_index: usize, index: u8, is_local: bool) -> Result<u8, CompileError> {
+        let state = &self.states[state_index];
+
+        // A closure referencing the same variable several times only captures it once
+        let existing = state.upvalues.iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+        if let Some(existing) = existing {
+            return Ok(existing as u8);
+        }
+
+        if state.upvalues.len() > u8::MAX as usize {
+            return Err(self.error_at(self.previous, self.previous_line, "Too many closure variables in function."));
+        }
+
+        let state = &mut self.states[state_index];
+        state.upvalues.push(Upvalue { index, is_local });
+        state.function.upvalue_count = state.upvalues.len();
+
+        Ok((state.upvalues.len() - 1) as u8)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_byte(OpCode::DefineGlobal as u8);
+        self.emit_byte(global);
+    }
+
+    fn statement(&mut self, top_level: bool) -> Result<(), CompileError> {
+        if self.match_keyword(KeywordKind::Print) {
+            let opcode = if self.print_newline { OpCode::Print } else { OpCode::PrintNoNewline };
+            self.print_statement(opcode)
+        } else if self.match_keyword(KeywordKind::If) {
+            self.if_statement()
+        } else if self.match_keyword(KeywordKind::Return) {
+            self.return_statement()
+        } else if self.match_keyword(KeywordKind::While) {
+            self.while_statement()
+        } else if self.match_keyword(KeywordKind::For) {
+            self.for_statement()
+        } else if self.match_keyword(KeywordKind::Break) {
+            self.break_statement()
+        } else if self.match_keyword(KeywordKind::Continue) {
+            self.continue_statement()
+        } else if self.match_token(Token::LeftBrace(0)) {
+            self.begin_scope();
+            self.block()?;
+            self.end_scope();
+
+            Ok(())
+        } else {
+            self.expression_statement(top_level)
+        }
+    }
+
+    // `opcode` is Print or PrintNoNewline, so other printing statements can share this
+    fn print_statement(&mut self, opcode: OpCode) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(Token::Semicolon(0), "Expect ';' after value.")?;
+        self.emit_byte(opcode as u8);
+
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), CompileError> {
+        self.consume(Token::LeftParen(0), "Expect '(' after 'if'.")?;
+        self.expression()?;
+        self.consume(Token::RightParen(0), "Expect ')' after condition.")?;
+
+        // The condition stays on the stack while jumping, so each branch pops it
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement(false)?;
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.match_keyword(KeywordKind::Else) {
+            self.statement(false)?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn return_statement(&mut self) -> Result<(), CompileError> {
+        if self.state().function_type == FunctionType::Script {
+            return Err(self.error_at(self.previous, self.previous_line, "Can't return from top-level code."));
+        }
+
+        if self.match_token(Token::Semicolon(0)) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                return Err(self.error_at(self.previous, self.previous_line, "Can't return a value from an initializer."));
+            }
+
+            self.expression()?;
+            self.consume(Token::Semicolon(0), "Expect ';' after return value.")?;
+            self.emit_byte(OpCode::Return as u8);
+        }
 
         Ok(())
     }
-}
 
+    fn while_statement(&mut self) -> Result<(), CompileError> {
+        let loop_start = self.current_chunk().len();
+
+        self.consume(Token::LeftParen(0), "Expect '(' after 'while'.")?;
+        self.expression()?;
+        self.consume(Token::RightParen(0), "Expect ')' after condition.")?;
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.loop_body(loop_start)?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.end_loop()
+    }
+
+    fn for_statement(&mut self) -> Result<(), CompileError> {
+        // Variables declared in the initializer are scoped to the loop
+        self.begin_scope();
+
+        self.consume(Token::LeftParen(0), "Expect '(' after 'for'.")?;
+        if self.match_token(Token::Semicolon(0)) {
+            // No initializer
+        } else if self.match_keyword(KeywordKind::Var) {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement(false)?;
+        }
+
+        let mut loop_start = self.current_chunk().len();
+
+        // An empty condition loops forever
+        let mut exit_jump = None;
+        if !self.match_token(Token::Semicolon(0)) {
+            self.expression()?;
+            self.consume(Token::Semicolon(0), "Expect ';' after loop condition.")?;
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        // The increment is compiled before the body but runs after it, so jump over it now and
+        // have the body loop back to it
+        if !self.match_token(Token::RightParen(0)) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().len();
+
+            self.expression()?;
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(Token::RightParen(0), "Expect ')' after for clauses.")?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.loop_body(loop_start)?;
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        self.end_loop()?;
+        self.end_scope();
+
+        Ok(())
+    }
+
+    fn loop_body(&mut self, start: usize) -> Result<(), CompileError> {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(LoopState { start, scope_depth, break_jumps: Vec::new() });
+
+        self.statement(false)
+    }
+
+    // Points every `break` in the loop just compiled to the next instruction
+    fn end_loop(&mut self) -> Result<(), CompileError> {
+        let loop_state = self.state_mut().loops.pop().unwrap();
+        for break_jump in loop_state.break_jumps {
+            self.patch_jump(break_jump)?;
+        }
+
+        Ok(())
+    }
+
+    // Emits the instructions discarding locals deeper than `depth`, without forgetting them, for
+    // jumps out of their scope
+    fn discard_locals(&mut self, depth: usize) {
+        let captured: Vec<bool> = self.state().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|local_depth| local_depth > depth))
+            .map(|local| local.is_captured)
+            .collect();
+
+        // Runs of uncaptured locals are popped together
+        let mut pending_pops: usize = 0;
+        for is_captured in captured {
+            if is_captured {
+                self.emit_pops(pending_pops);
+                pending_pops = 0;
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                pending_pops += 1;
+            }
+        }
+        self.emit_pops(pending_pops);
+    }
+
+    fn emit_pops(&mut self, mut count: usize) {
+        while count > 1 {
+            let n = count.min(u8::MAX as usize);
+            self.emit_byte(OpCode::PopN as u8);
+            self.emit_byte(n as u8);
+            count -= n;
+        }
+
+        if count == 1 {
+            self.emit_byte(OpCode::Pop as u8);
+        }
+    }
+
+    fn break_statement(&mut self) -> Result<(), CompileError> {
+        let depth = match self.state().loops.last() {
+            Some(loop_state) => loop_state.scope_depth,
+            None => return Err(self.error_at(self.previous, self.previous_line, "Can't use 'break' outside of a loop.")),
+        };
+        self.consume(Token::Semicolon(0), "Expect ';' after 'break'.")?;
+
+        self.discard_locals(depth);
+        let break_jump = self.emit_jump(OpCode::Jump);
+        self.state_mut().loops.last_mut().unwrap().break_jumps.push(break_jump);
+
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<(), CompileError> {
+        let (start, depth) = match self.state().loops.last() {
+            Some(loop_state) => (loop_state.start, loop_state.scope_depth),
+            None => return Err(self.error_at(self.previous, self.previous_line, "Can't use 'continue' outside of a loop.")),
+        };
+        self.consume(Token::Semicolon(0), "Expect ';' after 'continue'.")?;
+
+        self.discard_locals(depth);
+        self.emit_loop(start)
+    }
+
+    fn expression_statement(&mut self, top_level: bool) -> Result<(), CompileError> {
+        self.expression()?;
+
+        // A final top-level expression without a semicolon is the script's result
+        if top_level && self.result_expression && self.check(Token::EOF(0)) {
+            self.emit_byte(OpCode::Return as u8);
+            return Ok(());
+        }
+
+        self.consume(Token::Semicolon(0), "Expect ';' after expression.")?;
+
+        // Discard the result so locals keep their stack slots
+        self.emit_byte(OpCode::Pop as u8);
+
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<(), CompileError> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), CompileError> {
+        self.named_variable(self.lexeme(self.previous), can_assign)
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) -> Result<(), CompileError> {
+        let current = self.states.len() - 1;
+
+        let (get_op, set_op, operand) = if let Some(slot) = self.resolve_local(current, name)? {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(current, name)? {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+        } else {
+            (OpCode::GetGlobal, OpCode::SetGlobal, self.identifier_constant(name)?)
+        };
+
+        if can_assign && self.match_token(Token::Equal(0)) {
+            self.expression()?;
+            self.emit_byte(set_op as u8);
+        } else if let Some(opcode) = self.match_compound_assignment(can_assign) {
+            // `a += b` is compiled as `a = a + b`
+            self.emit_byte(get_op as u8);
+            self.emit_byte(operand);
+            self.expression()?;
+            self.emit_byte(opcode as u8);
+            self.emit_byte(set_op as u8);
+        } else {
+            self.emit_byte(get_op as u8);
+        }
+        self.emit_byte(operand);
+
+        Ok(())
+    }
+
+    // Consumes a compound assignment operator such as `+=`, returning its arithmetic opcode
+    fn match_compound_assignment(&mut self, can_assign: bool) -> Option<OpCode> {
+        let opcode = match self.current {
+            Token::PlusEqual(_) => OpCode::Add,
+            Token::MinusEqual(_) => OpCode::Subtract,
+            Token::StarEqual(_) => OpCode::Multiply,
+            Token::SlashEqual(_) => OpCode::Divide,
+            Token::PercentEqual(_) => OpCode::Modulo,
+            _ => return None,
+        };
+        if !can_assign {
+            return None;
+        }
+
+        self.advance();
+        Some(opcode)
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        let lexeme = self.lexeme(self.previous);
+
+        // The scanner already checked the digits of hexadecimal and binary literals
+        let radix = match lexeme.get(..2) {
+            Some("0x" | "0X") => Some(16),
+            Some("0b" | "0B") => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            let value = i64::from_str_radix(&lexeme[2..], radix)
+                .map_err(|_| self.error_at(self.previous, self.previous_line, "Number literal is too large."))?;
+            return self.emit_number(Value::Int(value));
+        }
+
+        // Literals without a fractional part are integers, unless they're too large for one
+        if let Ok(value) = lexeme.parse::<i64>() {
+            return self.emit_number(Value::Int(value));
+        }
+
+        let value = lexeme.parse::<f64>()
+            .map_err(|_| self.error_at(self.previous, self.previous_line, "Invalid number literal."))?;
+        self.emit_number(Value::Number(value))
+    }
+
+    fn emit_number(&mut self, value: Value) -> Result<(), CompileError> {
+        // Small integers are stored in the instruction itself instead of the constant pool
+        if let Value::Int(small @ 0..=255) = value {
+            self.emit_byte(OpCode::SmallInt as u8);
+            self.emit_byte(small as u8);
+
+            return Ok(());
+        }
+
+        self.emit_constant(value)
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        // Trim the surrounding quotes, either one or three on each side
+        let lexeme = self.lexeme(self.previous);
+        let quotes = if lexeme.len() >= 6 && lexeme.starts_with("\"\"\"") { 3 } else { 1 };
+        let value = &lexeme[quotes..lexeme.len() - quotes];
+
+        let value = self.strings.borrow_mut().intern(value);
+        self.emit_constant(Value::Str(value))?;
+
+        Ok(())
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        match self.previous {
+            Token::Keyword(_, KeywordKind::Nil) => self.emit_byte(OpCode::Nil as u8),
+            Token::Keyword(_, KeywordKind::True) => self.emit_byte(OpCode::True as u8),
+            Token::Keyword(_, KeywordKind::False) => self.emit_byte(OpCode::False as u8),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn and(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        // If the left operand is falsey it's the result, so skip the right operand
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::And)?;
+
+        self.patch_jump(end_jump)
+    }
+
+    fn or(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        // If the left operand is truthy it's the result, so jump over the right operand
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::Or)?;
+
+        self.patch_jump(end_jump)
+    }
+
+    fn conditional(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        // Same shape as an if statement, except each branch leaves its value on the stack
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.expression()?;
+        self.consume(Token::Colon(0), "Expect ':' after then branch of conditional expression.")?;
+
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        // Parsing the else branch at the same precedence makes nested conditionals right-associative
+        self.parse_precedence(Precedence::Conditional)?;
+
+        self.patch_jump(end_jump)
+    }
+
+    fn call(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        let arg_count = self.argument_list()?;
+
+        self.emit_byte(OpCode::Call as u8);
+        self.emit_byte(arg_count);
+
+        Ok(())
+    }
+
+    fn dot(&mut self, can_assign: bool) -> Result<(), CompileError> {
+        self.consume(Token::Identifier(0, 0), "Expect property name after '.'.")?;
+        let name = self.identifier_constant(self.lexeme(self.previous))?;
+
+        if can_assign && self.match_token(Token::Equal(0)) {
+            self.expression()?;
+            self.emit_byte(OpCode::SetProperty as u8);
+            self.emit_byte(name);
+        } else if let Some(opcode) = self.match_compound_assignment(can_assign) {
+            // The instance stays on the stack under the current value, for the set
+            self.emit_byte(OpCode::Duplicate as u8);
+            self.emit_byte(OpCode::GetProperty as u8);
+            self.emit_byte(name);
+            self.expression()?;
+            self.emit_byte(opcode as u8);
+            self.emit_byte(OpCode::SetProperty as u8);
+            self.emit_byte(name);
+        } else if self.match_token(Token::LeftParen(0)) {
+            // Calling a method right away skips creating a bound method
+            let arg_count = self.argument_list()?;
+            self.emit_byte(OpCode::Invoke as u8);
+            self.emit_byte(name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_byte(OpCode::GetProperty as u8);
+            self.emit_byte(name);
+        }
+
+        Ok(())
+    }
+
+    fn list(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        let mut element_count: u8 = 0;
+
+        if !self.check(Token::RightBracket(0)) {
+            loop {
+                self.expression()?;
+
+                if element_count == u8::MAX {
+                    return Err(self.error_at(self.previous, self.previous_line, "Can't have more than 255 elements in a list literal."));
+                }
+                element_count += 1;
+
+                if !self.match_token(Token::Comma(0)) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightBracket(0), "Expect ']' after list elements.")?;
+
+        self.emit_byte(OpCode::BuildList as u8);
+        self.emit_byte(element_count);
+
+        Ok(())
+    }
+
+    fn subscript(&mut self, can_assign: bool) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(Token::RightBracket(0), "Expect ']' after index.")?;
+
+        if can_assign && self.match_token(Token::Equal(0)) {
+            self.expression()?;
+            self.emit_byte(OpCode::SetIndex as u8);
+        } else if let Some(opcode) = self.match_compound_assignment(can_assign) {
+            // The list and index stay on the stack under the current element, for the set
+            self.emit_byte(OpCode::Duplicate2 as u8);
+            self.emit_byte(OpCode::GetIndex as u8);
+            self.expression()?;
+            self.emit_byte(opcode as u8);
+            self.emit_byte(OpCode::SetIndex as u8);
+        } else {
+            self.emit_byte(OpCode::GetIndex as u8);
+        }
+
+        Ok(())
+    }
+
+    fn this(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        if self.classes.is_empty() {
+            return Err(self.error_at(self.previous, self.previous_line, "Can't use 'this' outside of a class."));
+        }
+
+        // `this` is never assignable
+        self.variable(false)
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        match self.classes.last() {
+            None => {
+                return Err(self.error_at(self.previous, self.previous_line, "Can't use 'super' outside of a class."));
+            }
+            Some(class) if !class.has_superclass => {
+                let message = "Can't use 'super' in a class with no superclass.";
+                return Err(self.error_at(self.previous, self.previous_line, message));
+            }
+            Some(_) => {}
+        }
+
+        self.consume(Token::Dot(0), "Expect '.' after 'super'.")?;
+        self.consume(Token::Identifier(0, 0), "Expect superclass method name.")?;
+        let name = self.identifier_constant(self.lexeme(self.previous))?;
+
+        // The method is looked up on the superclass but bound to the current receiver
+        self.named_variable("this", false)?;
+        if self.match_token(Token::LeftParen(0)) {
+            let arg_count = self.argument_list()?;
+            self.named_variable("super", false)?;
+            self.emit_byte(OpCode::SuperInvoke as u8);
+            self.emit_byte(name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable("super", false)?;
+            self.emit_byte(OpCode::GetSuper as u8);
+            self.emit_byte(name);
+        }
+
+        Ok(())
+    }
+
+    fn argument_list(&mut self) -> Result<u8, CompileError> {
+        let mut arg_count: u8 = 0;
+
+        if !self.check(Token::RightParen(0)) {
+            loop {
+                self.expression()?;
+
+                if arg_count == u8::MAX {
+                    return Err(self.error_at(self.previous, self.previous_line, "Can't have more than 255 arguments."));
+                }
+                arg_count += 1;
+
+                if !self.match_token(Token::Comma(0)) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightParen(0), "Expect ')' after arguments.")?;
+
+        Ok(arg_count)
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        self.expression()?;
+        self.consume(Token::RightParen(0), "Expect ')' after expression.")
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        let operator = self.previous;
+        let operand_start = self.current_chunk().len();
+        let operand_constants = self.current_chunk().constants().len();
+
+        // Compile the operand
+        self.parse_precedence(Precedence::Unary)?;
+
+        match operator {
+            Token::Minus(_) => self.emit_negate(operand_start, operand_constants)?,
+            Token::Bang(_) => self.emit_byte(OpCode::Not as u8),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn binary(&mut self, _can_assign: bool) -> Result<(), CompileError> {
+        let operator = self.previous;
+        let rule = Self::get_rule(operator);
+        let left_start = self.operand_start;
+        let left_constants = self.operand_constants;
+        let right_start = self.current_chunk().len();
+
+        // Compile the right operand
+        self.parse_precedence(rule.precedence.next())?;
+
+        match operator {
+            Token::Plus(_) => self.emit_arithmetic(OpCode::Add, left_start, left_constants, right_start)?,
+            Token::Minus(_) => self.emit_arithmetic(OpCode::Subtract, left_start, left_constants, right_start)?,
+            Token::Star(_) => self.emit_arithmetic(OpCode::Multiply, left_start, left_constants, right_start)?,
+            Token::Slash(_) => self.emit_arithmetic(OpCode::Divide, left_start, left_constants, right_start)?,
+            Token::Percent(_) => self.emit_arithmetic(OpCode::Modulo, left_start, left_constants, right_start)?,
+            Token::EqualEqual(_) => self.emit_byte(OpCode::Equal as u8),
+            Token::BangEqual(_) => {
+                self.emit_byte(OpCode::Equal as u8);
+                self.emit_byte(OpCode::Not as u8);
+            }
+            Token::Greater(_) => self.emit_byte(OpCode::Greater as u8),
+            Token::GreaterEqual(_) => {
+                self.emit_byte(OpCode::Less as u8);
+                self.emit_byte(OpCode::Not as u8);
+            }
+            Token::Less(_) => self.emit_byte(OpCode::Less as u8),
+            Token::LessEqual(_) => {
+                self.emit_byte(OpCode::Greater as u8);
+                self.emit_byte(OpCode::Not as u8);
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // Emits a negation, or the negated number in place of the operand when it's a literal, dropping
+    // the constants the operand added from `operand_constants` on
+    fn emit_negate(&mut self, operand_start: usize, operand_constants: usize) -> Result<(), CompileError> {
+        let chunk = &self.state().function.chunk;
+        let negated = match chunk.read_constant_push(operand_start) {
+            Some((value, length)) if operand_start + length == chunk.len() => match value {
+                // Like the VM, the one integer without a negation falls back to floating point
+                Value::Int(value) => Some(value.checked_neg().map_or(Value::Number(-(value as f64)), Value::Int)),
+                Value::Number(value) => Some(Value::Number(-value)),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match negated {
+            Some(result) => {
+                self.current_chunk().truncate(operand_start);
+                self.current_chunk().truncate_constants(operand_constants);
+                self.emit_number(result)
+            }
+            None => {
+                self.emit_byte(OpCode::Negate as u8);
+                Ok(())
+            }
+        }
+    }
+
+    // Emits `opcode`, or its result in place of both operands when they're number literals. The
+    // operands' constants were added to the pool from `left_constants` on, and are dropped with them.
+    fn emit_arithmetic(
+        &mut self,
+        opcode: OpCode,
+        left_start: usize,
+        left_constants: usize,
+        right_start: usize,
+    ) -> Result<(), CompileError> {
+        let chunk = &self.state().function.chunk;
+        let folded = match (chunk.read_constant_push(left_start), chunk.read_constant_push(right_start)) {
+            (Some((a, a_length)), Some((b, b_length)))
+                if left_start + a_length == right_start && right_start + b_length == chunk.len() => {
+                Self::fold_arithmetic(opcode, a, b)
+            }
+            _ => None,
+        };
+
+        match folded {
+            Some(result) => {
+                self.current_chunk().truncate(left_start);
+                self.current_chunk().truncate_constants(left_constants);
+                self.emit_number(result)
+            }
+            None => {
+                self.emit_byte(opcode as u8);
+                Ok(())
+            }
+        }
+    }
+
+    // Computes `a opcode b` the way the VM would, or None if it has to be left to the VM
+    fn fold_arithmetic(opcode: OpCode, a: Value, b: Value) -> Option<Value> {
+        // Division by zero is still reported when the program runs
+        if matches!(opcode, OpCode::Divide | OpCode::Modulo) && b.as_number()? == 0.0 {
+            return None;
+        }
+
+        // Integer results that overflow fall back to floating point
+        if let (Value::Int(a), Value::Int(b)) = (&a, &b) {
+            let result = match opcode {
+                OpCode::Add => a.checked_add(*b),
+                OpCode::Subtract => a.checked_sub(*b),
+                OpCode::Multiply => a.checked_mul(*b),
+                OpCode::Divide => a.checked_div(*b),
+                OpCode::Modulo => a.checked_rem(*b),
+                _ => None,
+            };
+            if let Some(result) = result {
+                return Some(Value::Int(result));
+            }
+        }
+
+        let (a, b) = (a.as_number()?, b.as_number()?);
+        match opcode {
+            OpCode::Add => Some(Value::Number(a + b)),
+            OpCode::Subtract => Some(Value::Number(a - b)),
+            OpCode::Multiply => Some(Value::Number(a * b)),
+            OpCode::Divide => Some(Value::Number(a / b)),
+            OpCode::Modulo => Some(Value::Number(a % b)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    // The opcodes of the script's chunk, in order
+    fn opcodes(source: &str) -> Vec<OpCode> {
+        chunk_opcodes(&Compiler::compile_to_chunk(source).unwrap())
+    }
+
+    fn chunk_opcodes(chunk: &Chunk) -> Vec<OpCode> {
+        let mut opcodes = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.len() {
+            opcodes.push(OpCode::try_from(chunk.code()[offset]).unwrap());
+            offset += chunk.write_instruction(&mut std::io::sink(), offset).unwrap();
+        }
+
+        opcodes
+    }
+
+    // The first function declared in `chunk`
+    fn first_function(chunk: &Chunk) -> Rc<Function> {
+        chunk.constants().iter()
+            .find_map(|constant| match constant {
+                Value::Function(function) => Some(function.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn error_messages(source: &str) -> Vec<String> {
+        Compiler::compile_to_chunk(source).err().unwrap().iter().map(|err| err.to_string()).collect()
+    }
+
+    fn first_error_location(source: &str) -> Option<(usize, usize)> {
+        Compiler::compile_to_chunk(source).err().unwrap()[0].location()
+    }
+
+    #[test]
+    fn bytecode_is_tagged_with_the_line_of_its_token() {
+        let chunk = Compiler::compile_to_chunk("\n\nprint 1;\nprint 2;").unwrap();
+
+        assert_eq!(chunk.get_line(0), 3);
+        assert_eq!(chunk.last_line(), Some(4));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        assert_eq!(first_error_location("print 1;\nvar = ;"), Some((2, 5)));
+        assert_eq!(first_error_location("print 1;\n  @"), Some((2, 3)));
+        assert_eq!(first_error_location("print 1;\nprint \"abc"), Some((2, 7)));
+        assert_eq!(first_error_location("print 0b12;"), Some((1, 7)));
+    }
+
+    #[test]
+    fn radix_literals_compile_to_their_value() {
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+
+        assert_eq!(vm.interpret_value("0xFF").unwrap(), Value::Int(255));
+        assert_eq!(vm.interpret_value("0b1010").unwrap(), Value::Int(10));
+    }
+
+    #[test]
+    fn malformed_number_is_reported_once() {
+        let errors = Compiler::compile_to_chunk("print 0xG;\nprint 1 + 0b2;").err().unwrap();
+
+        let literals: Vec<_> = errors.iter()
+            .map(|err| match err {
+                CompileError::ScannerError(ScannerError::MalformedNumber { literal, .. }) => literal.as_str(),
+                err => panic!("unexpected error: {}", err),
+            })
+            .collect();
+        assert_eq!(literals, ["0xG", "0b2"]);
+    }
+
+    #[test]
+    fn local_cannot_read_itself_in_its_initializer() {
+        assert_eq!(error_messages("{ var a = a; }"), [
+            "Failed to compile: [line 1] Error at 'a': Can't read local variable in its own initializer.",
+        ]);
+    }
+
+    #[test]
+    fn return_at_top_level_is_an_error() {
+        assert_eq!(error_messages("return 1;"), [
+            "Failed to compile: [line 1] Error at 'return': Can't return from top-level code.",
+        ]);
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_an_error() {
+        assert_eq!(error_messages("break;"), [
+            "Failed to compile: [line 1] Error at 'break': Can't use 'break' outside of a loop.",
+        ]);
+    }
+
+    #[test]
+    fn compilation_reports_an_error_per_broken_statement() {
+        assert_eq!(error_messages("print ;\nvar = 1;\nprint 2;"), [
+            "Failed to compile: [line 1] Error at ';': Expect expression.",
+            "Failed to compile: [line 2] Error at '=': Expect variable name.",
+        ]);
+    }
+
+    #[test]
+    fn only_a_top_level_statement_can_end_the_script_without_a_semicolon() {
+        assert_eq!(error_messages("if (true) 1"), [
+            "Failed to compile: [line 1] Error at end: Expect ';' after expression.",
+        ]);
+        assert_eq!(error_messages("while (false) 1"), [
+            "Failed to compile: [line 1] Error at end: Expect ';' after expression.",
+        ]);
+    }
+
+    #[test]
+    fn without_result_expressions_the_last_statement_needs_a_semicolon() {
+        let mut compiler = Compiler::new("1 + 2", Rc::new(RefCell::new(Interner::new())));
+        compiler.set_result_expression(false);
+        let errors = compiler.compile().err().unwrap();
+
+        assert_eq!(errors.iter().map(|err| err.to_string()).collect::<Vec<_>>(), [
+            "Failed to compile: [line 1] Error at end: Expect ';' after expression.",
+        ]);
+    }
+
+    #[test]
+    fn too_many_locals_is_reported_once() {
+        let locals: String = (0..300).map(|i| format!("var a{}; ", i)).collect();
+
+        assert_eq!(error_messages(&format!("{{ {}}}", locals)), [
+            "Failed to compile: [line 1] Error at 'a255': Too many local variables in function.",
+        ]);
+    }
+
+    #[test]
+    fn closing_a_scope_pops_its_locals_together() {
+        use OpCode::*;
+        let source = "{ var a; var b; var c; var d; var e; }";
+
+        assert_eq!(opcodes(source), [Nil, Nil, Nil, Nil, Nil, PopN, Nil, Return]);
+
+        // An OP_POP per local would take five bytes instead of OP_POP_N's two
+        let unoptimized_len = 5 + 5 + 2;
+        assert_eq!(Compiler::compile_to_chunk(source).unwrap().len(), unoptimized_len - 3);
+    }
+
+    #[test]
+    fn disassembled_jumps_show_their_target() {
+        let chunk = Compiler::compile_to_chunk("if (true) print 1;").unwrap();
+        let mut out = Vec::new();
+        chunk.write_disassembly(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // The condition's jump lands on the else branch's OP_POP, the then branch's on the end
+        assert_eq!(out, concat!(
+            "<script>: \n",
+            "0x000000    1 OP_TRUE\n",
+            "0x000001    | OP_JUMP_IF_FALSE 0x000001 -> 0x00000b\n",
+            "0x000004    | OP_POP\n",
+            "0x000005    | OP_SMALL_INT 1\n",
+            "0x000007    | OP_PRINT\n",
+            "0x000008    | OP_JUMP 0x000008 -> 0x00000c\n",
+            "0x00000b    | OP_POP\n",
+            "0x00000c    | OP_NIL\n",
+            "0x00000d    | OP_RETURN\n",
+        ));
+    }
+
+    #[test]
+    fn chained_accesses_compile_left_to_right() {
+        use OpCode::*;
+
+        assert_eq!(opcodes("obj.method().field;"), [GetGlobal, Invoke, GetProperty, Pop, Nil, Return]);
+    }
+
+    #[test]
+    fn zero_and_one_compile_without_pool_constants() {
+        use OpCode::*;
+
+        assert_eq!(opcodes("0; 1;"), [SmallInt, Pop, SmallInt, Pop, Nil, Return]);
+        assert!(Compiler::compile_to_chunk("0; 1;").unwrap().constants().is_empty());
+
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        assert_eq!(vm.interpret_value("0").unwrap(), Value::Int(0));
+        assert_eq!(vm.interpret_value("1").unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn prefix_minus_binds_tighter_than_infix_minus() {
+        use OpCode::*;
+
+        // Globals keep the operators from being folded away
+        assert_eq!(opcodes("-a - -b;"), [GetGlobal, Negate, GetGlobal, Negate, Subtract, Pop, Nil, Return]);
+        assert_eq!(opcodes("- -a;"), [GetGlobal, Negate, Negate, Pop, Nil, Return]);
+
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        assert_eq!(vm.interpret_value("-1 - -2").unwrap(), Value::Int(1));
+        assert_eq!(vm.interpret_value("- -1").unwrap(), Value::Int(1));
+        assert_eq!(vm.interpret_value("var a = 1; var b = 2; -a - -b").unwrap(), Value::Int(1));
+        assert_eq!(vm.interpret_value("- -a").unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn keywords_cannot_be_declared_as_names() {
+        assert_eq!(error_messages("var print = 1;"), [
+            "Failed to compile: [line 1] Error at 'print': 'print' is a reserved keyword and can't be used as a variable name.",
+        ]);
+        assert_eq!(error_messages("fun f(while) {}"), [
+            "Failed to compile: [line 1] Error at 'while': 'while' is a reserved keyword and can't be used as a parameter name.",
+        ]);
+    }
+
+    #[test]
+    fn function_without_a_return_ends_by_returning_nil() {
+        use OpCode::*;
+
+        let chunk = Compiler::compile_to_chunk("fun f() { print 1; }").unwrap();
+        let function = first_function(&chunk);
+        assert_eq!(chunk_opcodes(&function.chunk), [SmallInt, Print, Nil, Return]);
+
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        assert_eq!(vm.interpret_value("fun g() {}\ng()").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn arithmetic_on_literals_folds_to_one_constant() {
+        use OpCode::*;
+
+        assert_eq!(opcodes("2 + 3;"), [SmallInt, Pop, Nil, Return]);
+        assert_eq!(Compiler::compile_to_chunk("2 + 3").unwrap().read_constant_push(0), Some((Value::Int(5), 2)));
+
+        // Only the result is left in the pool, not the operands it replaced
+        let chunk = Compiler::compile_to_chunk("1.5 * 2.0 + 1000;").unwrap();
+        assert_eq!(chunk_opcodes(&chunk), [Constant, Pop, Nil, Return]);
+        assert_eq!(chunk.constants(), [Value::Number(1003.0)]);
+    }
+
+    #[test]
+    fn folded_operands_keep_constants_used_elsewhere() {
+        let chunk = Compiler::compile_to_chunk("print 2.5;\nprint 2.5 + 1.5;").unwrap();
+
+        assert_eq!(chunk.constants(), [Value::Number(2.5), Value::Number(4.0)]);
+
+        // A dropped operand is added again, not looked up at its old slot
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        assert_eq!(vm.interpret_value("var a = 1.5 + 2.5;\nvar b = 1.5;\na + b").unwrap(), Value::Number(5.5));
+    }
+
+    #[test]
+    fn negated_literals_fold_into_the_constant() {
+        use OpCode::*;
+
+        // One instruction instead of OP_SMALL_INT, OP_NEGATE
+        assert_eq!(opcodes("-5;"), [Constant, Pop, Nil, Return]);
+        assert_eq!(Compiler::compile_to_chunk("-5;").unwrap().constants(), [Value::Int(-5)]);
+
+        // The operand's own constant is dropped along with its push
+        let chunk = Compiler::compile_to_chunk("-1000; -(2.5);").unwrap();
+        assert_eq!(chunk_opcodes(&chunk), [Constant, Pop, Constant, Pop, Nil, Return]);
+        assert_eq!(chunk.constants(), [Value::Int(-1000), Value::Number(-2.5)]);
+    }
+
+    #[test]
+    fn compound_assignment_to_an_element_keeps_the_list_and_index() {
+        use OpCode::*;
+
+        assert_eq!(opcodes("l[0] += 1;"), [
+            GetGlobal, SmallInt, Duplicate2, GetIndex, SmallInt, Add, SetIndex, Pop, Nil, Return,
+        ]);
+    }
+
+    #[test]
+    fn compiler_uses_the_scanner_it_is_given() {
+        let strings = Rc::new(RefCell::new(Interner::new()));
+
+        let mut scanner = Scanner::new("var abc = 1;");
+        scanner.set_max_token_length(3);
+        let mut compiler = Compiler::from_scanner(scanner, strings.clone());
+        assert!(compiler.compile().is_ok());
+
+        // The scanner's settings hold for every compilation
+        let mut scanner = Scanner::new("var abcd = 1;");
+        scanner.set_max_token_length(3);
+        let mut compiler = Compiler::from_scanner(scanner, strings);
+        for _ in 0..2 {
+            let errors = compiler.compile().err().unwrap();
+            assert!(matches!(errors[..], [CompileError::ScannerError(ScannerError::TokenTooLong { line: 1, column: 5 })]));
+        }
+    }
+
+    #[test]
+    fn disassembled_closure_lists_its_captured_variables() {
+        let source = "fun outer() {\n  var a = 1;\n  var b = 2;\n  fun inner() { return a + b; }\n  return inner;\n}";
+        let chunk = Compiler::compile_to_chunk(source).unwrap();
+        let outer = first_function(&chunk);
+
+        let mut out = Vec::new();
+        outer.chunk.write_disassembly(&mut out).unwrap();
+
+        // Each captured local's slot follows OP_CLOSURE, and the next instruction starts after them
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "outer: \n",
+            "0x000000    2 OP_SMALL_INT 1\n",
+            "0x000002    3 OP_SMALL_INT 2\n",
+            "0x000004    4 OP_CLOSURE 0 <fn inner>\n",
+            "0x000006    |   local 1\n",
+            "0x000008    |   local 2\n",
+            "0x00000a    5 OP_GET_LOCAL 3\n",
+            "0x00000c    | OP_RETURN\n",
+            "0x00000d    6 OP_NIL\n",
+            "0x00000e    | OP_RETURN\n",
+        ));
+    }
+
+    #[test]
+    fn class_cannot_inherit_from_itself() {
+        assert_eq!(error_messages("class A < A {}"), [
+            "Failed to compile: [line 1] Error at 'A': A class can't inherit from itself.",
+        ]);
+    }
+
+    #[test]
+    fn print_without_a_newline_compiles_to_its_own_opcode() {
+        use OpCode::*;
+
+        let mut compiler = Compiler::new("print 1;", Rc::new(RefCell::new(Interner::new())));
+        compiler.set_print_newline(false);
+        let function = compiler.compile().unwrap();
+
+        assert_eq!(chunk_opcodes(&function.chunk), [SmallInt, PrintNoNewline, Nil, Return]);
+        assert_eq!(opcodes("print 1;"), [SmallInt, Print, Nil, Return]);
+    }
+}
@@ -0,0 +1,42 @@
+// Renders the source around an error, pointing at the offending column:
+//
+//    2 | var = ;
+//      |     ^
+pub fn snippet(source: &str, line: usize, column: usize) -> String {
+    let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    let gutter = format!("{:>4}", line);
+
+    format!(
+        "{} | {}\n{} | {}^",
+        gutter,
+        text,
+        " ".repeat(gutter.len()),
+        " ".repeat(column.saturating_sub(1)),
+    )
+}
+
+// 1-based column of the byte at `offset`, counted in characters
+pub fn column_at(source: &str, offset: usize) -> usize {
+    let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+    source[line_start..offset].chars().count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_points_at_the_column() {
+        let source = "print 1;\nvar = ;\n";
+
+        assert_eq!(snippet(source, 2, 5), "   2 | var = ;\n     |     ^");
+    }
+
+    #[test]
+    fn column_counts_characters_from_the_line_start() {
+        let source = "print 1;\nvar é = ;";
+
+        assert_eq!(column_at(source, 0), 1);
+        assert_eq!(column_at(source, source.find('=').unwrap()), 7);
+    }
+}
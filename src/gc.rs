@@ -0,0 +1,345 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+use crate::object::{BoundMethod, Class, Closure, Instance, Upvalue};
+use crate::value::Value;
+
+// Objects are reference counted, which frees everything except reference cycles. The heap keeps
+// a weak reference to every object that can take part in a cycle, and a collection breaks the
+// cycles between unreachable objects by clearing their references, letting `Rc` free them.
+// Besides the VM's roots, any object with more strong references than other objects account for
+// is held from outside the heap, e.g. by a value returned to the embedder, and is a root too.
+pub struct Heap {
+    objects: Vec<HeapObject>,
+    // Number of tracked objects that triggers the next collection
+    next_gc: usize,
+}
+
+enum HeapObject {
+    Closure(Weak<Closure>),
+    Class(Weak<Class>),
+    Instance(Weak<Instance>),
+    BoundMethod(Weak<BoundMethod>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    List(Weak<RefCell<Vec<Value>>>),
+}
+
+impl HeapObject {
+    fn is_alive(&self) -> bool {
+        match self {
+            HeapObject::Closure(closure) => closure.strong_count() > 0,
+            HeapObject::Class(class) => class.strong_count() > 0,
+            HeapObject::Instance(instance) => instance.strong_count() > 0,
+            HeapObject::BoundMethod(bound_method) => bound_method.strong_count() > 0,
+            HeapObject::Upvalue(upvalue) => upvalue.strong_count() > 0,
+            HeapObject::List(list) => list.strong_count() > 0,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            HeapObject::Closure(closure) => closure.strong_count(),
+            HeapObject::Class(class) => class.strong_count(),
+            HeapObject::Instance(instance) => instance.strong_count(),
+            HeapObject::BoundMethod(bound_method) => bound_method.strong_count(),
+            HeapObject::Upvalue(upvalue) => upvalue.strong_count(),
+            HeapObject::List(list) => list.strong_count(),
+        }
+    }
+
+    fn address(&self) -> *const () {
+        match self {
+            HeapObject::Closure(closure) => closure.as_ptr() as *const (),
+            HeapObject::Class(class) => class.as_ptr() as *const (),
+            HeapObject::Instance(instance) => instance.as_ptr() as *const (),
+            HeapObject::BoundMethod(bound_method) => bound_method.as_ptr() as *const (),
+            HeapObject::Upvalue(upvalue) => upvalue.as_ptr() as *const (),
+            HeapObject::List(list) => list.as_ptr() as *const (),
+        }
+    }
+
+    // Calls `visit` with the address of every tracked object this one holds a reference to
+    fn for_each_reference(&self, mut visit: impl FnMut(*const ())) {
+        match self {
+            HeapObject::Closure(closure) => {
+                if let Some(closure) = closure.upgrade() {
+                    closure.upvalues.iter().for_each(|upvalue| visit(Rc::as_ptr(upvalue) as *const ()));
+                }
+            }
+            HeapObject::Class(class) => {
+                if let Some(class) = class.upgrade() {
+                    class.methods.borrow().values().for_each(|method| visit(Rc::as_ptr(method) as *const ()));
+                }
+            }
+            HeapObject::Instance(instance) => {
+                if let Some(instance) = instance.upgrade() {
+                    visit(Rc::as_ptr(&instance.class) as *const ());
+                    instance.fields.borrow().values().filter_map(object_address).for_each(visit);
+                }
+            }
+            HeapObject::BoundMethod(bound_method) => {
+                if let Some(bound_method) = bound_method.upgrade() {
+                    object_address(&bound_method.receiver).into_iter().for_each(&mut visit);
+                    visit(Rc::as_ptr(&bound_method.method) as *const ());
+                }
+            }
+            HeapObject::Upvalue(upvalue) => {
+                if let Some(upvalue) = upvalue.upgrade() {
+                    if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                        object_address(value).into_iter().for_each(visit);
+                    }
+                }
+            }
+            HeapObject::List(list) => {
+                if let Some(list) = list.upgrade() {
+                    list.borrow().iter().filter_map(object_address).for_each(visit);
+                }
+            }
+        }
+    }
+
+    // Drops every reference the object holds. Closures and bound methods are immutable, but any
+    // cycle through them also passes through a class, an instance or an upvalue.
+    fn clear(&self) {
+        match self {
+            HeapObject::Class(class) => {
+                if let Some(class) = class.upgrade() {
+                    class.methods.borrow_mut().clear();
+                }
+            }
+            HeapObject::Instance(instance) => {
+                if let Some(instance) = instance.upgrade() {
+                    instance.fields.borrow_mut().clear();
+                }
+            }
+            HeapObject::Upvalue(upvalue) => {
+                if let Some(upvalue) = upvalue.upgrade() {
+                    *upvalue.borrow_mut() = Upvalue::Closed(Value::Nil);
+                }
+            }
+            HeapObject::List(list) => {
+                if let Some(list) = list.upgrade() {
+                    list.borrow_mut().clear();
+                }
+            }
+            HeapObject::Closure(_) | HeapObject::BoundMethod(_) => {}
+        }
+    }
+}
+
+impl Heap {
+    const DEFAULT_THRESHOLD: usize = 1024;
+    const GROW_FACTOR: usize = 2;
+
+    pub fn new() -> Self {
+        Self::with_threshold(Self::DEFAULT_THRESHOLD)
+    }
+
+    pub fn with_threshold(threshold: usize) -> Self {
+        Self {
+            objects: Vec::new(),
+            next_gc: threshold,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.next_gc = threshold;
+    }
+
+    pub fn alloc_closure(&mut self, closure: Closure) -> Rc<Closure> {
+        let closure = Rc::new(closure);
+        self.objects.push(HeapObject::Closure(Rc::downgrade(&closure)));
+
+        closure
+    }
+
+    pub fn alloc_class(&mut self, class: Class) -> Rc<Class> {
+        let class = Rc::new(class);
+        self.objects.push(HeapObject::Class(Rc::downgrade(&class)));
+
+        class
+    }
+
+    pub fn alloc_instance(&mut self, instance: Instance) -> Rc<Instance> {
+        let instance = Rc::new(instance);
+        self.objects.push(HeapObject::Instance(Rc::downgrade(&instance)));
+
+        instance
+    }
+
+    pub fn alloc_bound_method(&mut self, bound_method: BoundMethod) -> Rc<BoundMethod> {
+        let bound_method = Rc::new(bound_method);
+        self.objects.push(HeapObject::BoundMethod(Rc::downgrade(&bound_method)));
+
+        bound_method
+    }
+
+    pub fn alloc_upvalue(&mut self, upvalue: Upvalue) -> Rc<RefCell<Upvalue>> {
+        let upvalue = Rc::new(RefCell::new(upvalue));
+        self.objects.push(HeapObject::Upvalue(Rc::downgrade(&upvalue)));
+
+        upvalue
+    }
+
+    pub fn alloc_list(&mut self, elements: Vec<Value>) -> Rc<RefCell<Vec<Value>>> {
+        let list = Rc::new(RefCell::new(elements));
+        self.objects.push(HeapObject::List(Rc::downgrade(&list)));
+
+        list
+    }
+
+    // Number of tracked objects that haven't been freed yet
+    pub fn object_count(&self) -> usize {
+        self.objects.iter().filter(|object| object.is_alive()).count()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_gc
+    }
+
+    // Frees every tracked object that isn't reachable from `roots`, `upvalues` or references held
+    // outside the heap, returning how many objects were freed
+    pub fn collect<'v>(
+        &mut self,
+        roots: impl IntoIterator<Item = &'v Value>,
+        upvalues: &[Rc<RefCell<Upvalue>>],
+    ) -> usize {
+        let before = self.object_count();
+        // Counted before marking, which holds extra references to the objects it visits
+        let external_roots: Vec<&HeapObject> = self.external_roots().collect();
+
+        let mut marker = Marker::default();
+        for root in roots {
+            marker.mark_value(root);
+        }
+        for upvalue in upvalues {
+            marker.mark_upvalue(upvalue);
+        }
+        for object in external_roots {
+            marker.mark_object(object);
+        }
+        marker.trace();
+
+        // Clearing an object may free others, so only drop dead entries afterwards
+        for object in self.objects.iter() {
+            if object.is_alive() && !marker.marked.contains(&object.address()) {
+                object.clear();
+            }
+        }
+        self.objects.retain(HeapObject::is_alive);
+
+        self.next_gc = (self.objects.len() * Self::GROW_FACTOR).max(Self::DEFAULT_THRESHOLD);
+
+        before - self.objects.len()
+    }
+
+    // Objects with strong references from outside the heap, found by subtracting the references
+    // tracked objects hold to each other from each object's strong count
+    fn external_roots(&self) -> impl Iterator<Item = &HeapObject> {
+        let mut internal_references: HashMap<*const (), usize> = HashMap::new();
+        for object in self.objects.iter() {
+            object.for_each_reference(|address| *internal_references.entry(address).or_insert(0) += 1);
+        }
+
+        self.objects.iter().filter(move |object| {
+            object.strong_count() > internal_references.get(&object.address()).copied().unwrap_or(0)
+        })
+    }
+}
+
+// Address of the tracked object a value refers to
+fn object_address(value: &Value) -> Option<*const ()> {
+    match value {
+        Value::Closure(closure) => Some(Rc::as_ptr(closure) as *const ()),
+        Value::Class(class) => Some(Rc::as_ptr(class) as *const ()),
+        Value::Instance(instance) => Some(Rc::as_ptr(instance) as *const ()),
+        Value::BoundMethod(bound_method) => Some(Rc::as_ptr(bound_method) as *const ()),
+        Value::List(list) => Some(Rc::as_ptr(list) as *const ()),
+        // None of the other values can reference a tracked object
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Marker {
+    marked: HashSet<*const ()>,
+    // Marked objects whose references haven't been traced yet
+    gray: Vec<Value>,
+}
+
+impl Marker {
+    // Returns whether the object at `address` was newly marked
+    fn mark(&mut self, address: *const ()) -> bool {
+        self.marked.insert(address)
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        if object_address(value).is_some_and(|address| self.mark(address)) {
+            self.gray.push(value.clone());
+        }
+    }
+
+    fn mark_object(&mut self, object: &HeapObject) {
+        let value = match object {
+            HeapObject::Closure(closure) => closure.upgrade().map(Value::Closure),
+            HeapObject::Class(class) => class.upgrade().map(Value::Class),
+            HeapObject::Instance(instance) => instance.upgrade().map(Value::Instance),
+            HeapObject::BoundMethod(bound_method) => bound_method.upgrade().map(Value::BoundMethod),
+            HeapObject::List(list) => list.upgrade().map(Value::List),
+            HeapObject::Upvalue(upvalue) => {
+                if let Some(upvalue) = upvalue.upgrade() {
+                    self.mark_upvalue(&upvalue);
+                }
+                return;
+            }
+        };
+
+        if let Some(value) = value {
+            self.mark_value(&value);
+        }
+    }
+
+    fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        if !self.mark(Rc::as_ptr(upvalue) as *const ()) {
+            return;
+        }
+
+        // Open upvalues point into the stack, which is a root already
+        if let Upvalue::Closed(value) = &*upvalue.borrow() {
+            self.mark_value(value);
+        }
+    }
+
+    fn trace(&mut self) {
+        while let Some(value) = self.gray.pop() {
+            match value {
+                Value::Closure(closure) => {
+                    for upvalue in closure.upvalues.iter() {
+                        self.mark_upvalue(upvalue);
+                    }
+                }
+                Value::Class(class) => {
+                    for method in class.methods.borrow().values() {
+                        self.mark_value(&Value::Closure(method.clone()));
+                    }
+                }
+                Value::Instance(instance) => {
+                    self.mark_value(&Value::Class(instance.class.clone()));
+                    for field in instance.fields.borrow().values() {
+                        self.mark_value(field);
+                    }
+                }
+                Value::BoundMethod(bound_method) => {
+                    self.mark_value(&bound_method.receiver);
+                    self.mark_value(&Value::Closure(bound_method.method.clone()));
+                }
+                Value::List(list) => {
+                    for element in list.borrow().iter() {
+                        self.mark_value(element);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
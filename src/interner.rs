@@ -0,0 +1,70 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// Every string value is allocated through the interner, so equal strings share one allocation
+// and can be compared by pointer
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<InternedString>,
+}
+
+// Lets the table be searched with a `&str` without allocating a `String` first
+#[derive(PartialEq, Eq, Hash)]
+struct InternedString(Rc<String>);
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, string: &str) -> Rc<String> {
+        if let Some(interned) = self.strings.get(string) {
+            return interned.0.clone();
+        }
+
+        self.insert(string.to_string())
+    }
+
+    // Same as `intern`, but reuses the buffer of a string that was just built
+    pub fn intern_owned(&mut self, string: String) -> Rc<String> {
+        if let Some(interned) = self.strings.get(string.as_str()) {
+            return interned.0.clone();
+        }
+
+        self.insert(string)
+    }
+
+    fn insert(&mut self, string: String) -> Rc<String> {
+        let interned = Rc::new(string);
+        self.strings.insert(InternedString(interned.clone()));
+
+        interned
+    }
+
+    // Drops the strings only the table itself still references
+    pub fn remove_unused(&mut self) {
+        self.strings.retain(|interned| Rc::strong_count(&interned.0) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_share_one_allocation() {
+        let mut interner = Interner::new();
+        let a = interner.intern("lox");
+        let b = interner.intern_owned("lox".to_string());
+
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &interner.intern("other")));
+    }
+}
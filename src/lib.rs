@@ -0,0 +1,30 @@
+//! A bytecode virtual machine for the Lox language.
+//!
+//! Source code is scanned into tokens, compiled into a [`Chunk`] of bytecode per function, and
+//! executed by the [`VM`].
+//!
+//! ```
+//! use rlox::{Value, VM};
+//!
+//! let mut vm = VM::new();
+//!
+//! assert_eq!(vm.interpret_value("1 + 2").unwrap(), Value::Int(3));
+//! ```
+
+pub mod chunk;
+pub mod compiler;
+pub mod diagnostics;
+pub mod interner;
+pub mod object;
+pub mod scanner;
+pub mod value;
+pub mod vm;
+mod gc;
+mod natives;
+mod stack;
+
+pub use chunk::{Chunk, OpCode};
+pub use compiler::Compiler;
+pub use scanner::{scan_tokens, Scanner};
+pub use value::Value;
+pub use vm::VM;
@@ -1,22 +1,87 @@
 use std::{io, env};
 use std::fs::File;
 use std::io::{BufRead, Read, Write};
-use crate::vm::VM;
+use std::cell::RefCell;
+use std::process::ExitCode;
+use std::rc::Rc;
+use rlox::Compiler;
+use rlox::compiler::CompileError;
+use rlox::diagnostics;
+use rlox::interner::Interner;
+use rlox::scanner::{Scanner, ScannerError, Token};
+use rlox::vm::{InterpretError, VM};
 
-mod chunk;
-mod vm;
-mod compiler;
-mod scanner;
+// Exit codes from sysexits.h
+const EX_USAGE: u8 = 64;
+const EX_DATAERR: u8 = 65;
+const EX_SOFTWARE: u8 = 70;
 
-fn repl() -> Result<(), io::Error> {
+// Reports a failed run and returns the matching exit code
+fn report(source: &str, result: Result<(), InterpretError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // Runtime errors are reported by the VM as they happen
+        Err(InterpretError::RuntimeError { .. }) => ExitCode::from(EX_SOFTWARE),
+        Err(InterpretError::CompileError(errors)) => {
+            report_compile_errors(source, &errors);
+            ExitCode::from(EX_DATAERR)
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(EX_SOFTWARE)
+        }
+    }
+}
+
+fn report_compile_errors(source: &str, errors: &[CompileError]) {
+    for err in errors {
+        eprintln!("{}", err);
+        if let Some((line, column)) = err.location() {
+            eprintln!("{}", diagnostics::snippet(source, line, column));
+        }
+    }
+}
+
+// Whether the input closes every brace, paren and string it opens, so it can be compiled.
+// Scanning it the way the compiler will keeps comments and triple-quoted strings in step.
+fn is_complete(input: &str) -> bool {
+    let (tokens, errors) = Scanner::new(input).scan_all();
+    if errors.iter().any(|err| matches!(err, ScannerError::UnterminatedString { .. })) {
+        return false;
+    }
+
+    let depth = tokens.iter().fold(0, |depth, token| match token {
+        Token::LeftParen(_) | Token::LeftBrace(_) | Token::LeftBracket(_) => depth + 1,
+        Token::RightParen(_) | Token::RightBrace(_) | Token::RightBracket(_) => depth - 1,
+        _ => depth,
+    });
+
+    // Unbalanced closing brackets are left for the compiler to report
+    depth <= 0
+}
+
+fn repl(trace: bool) -> Result<(), io::Error> {
     let stdin = io::stdin();
+    let mut vm = VM::new();
+    vm.set_trace(trace);
+
+    let mut buffer = String::new();
 
     print!("> ");
     _ = io::stdout().flush();
     for line in stdin.lock().lines() {
-        let _line = line.unwrap();
+        buffer.push_str(&line?);
+        buffer.push('\n');
 
-        // Do something...
+        if !is_complete(&buffer) {
+            print!("... ");
+            _ = io::stdout().flush();
+            continue;
+        }
+
+        // The session carries on after an error, so the exit code is dropped
+        _ = report(&buffer, vm.interpret(&buffer));
+        buffer.clear();
 
         print!("> ");
         _ = io::stdout().flush();
@@ -25,26 +90,101 @@ fn repl() -> Result<(), io::Error> {
     Ok(())
 }
 
-fn run_file(file_path: &str) -> Result<(), io::Error> {
+fn run_file(file_path: &str, trace: bool) -> Result<ExitCode, io::Error> {
     let mut buffer = String::new();
 
     let mut source_file = File::open(file_path)?;
     _ = source_file.read_to_string(&mut buffer)?;
 
-    let mut vm = VM::new(&buffer);
+    // Only the REPL prints a final expression that has no semicolon
+    let mut vm = VM::new();
+    vm.set_trace(trace);
+    vm.set_result_expression(false);
 
-    Ok(())
+    Ok(report(&buffer, vm.interpret(&buffer)))
 }
 
-fn main() -> Result<(), io::Error> {
-    let args: Vec<String> = env::args().collect();
+// Compiles the file and prints the bytecode of every function in it, without running anything
+fn dump_bytecode(file_path: &str) -> Result<ExitCode, io::Error> {
+    let mut buffer = String::new();
 
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            eprintln!("Usage: rlox [path]");
-            Ok(())
+    let mut source_file = File::open(file_path)?;
+    _ = source_file.read_to_string(&mut buffer)?;
+
+    let mut compiler = Compiler::new(&buffer, Rc::new(RefCell::new(Interner::new())));
+    compiler.set_result_expression(false);
+    match compiler.compile() {
+        Ok(function) => {
+            function.disassemble();
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(errors) => {
+            report_compile_errors(&buffer, &errors);
+            Ok(ExitCode::from(EX_DATAERR))
+        }
+    }
+}
+
+const USAGE: &str = "Usage: rlox [--dump-bytecode] [--trace] [--version] [--help] [path]";
+
+fn print_usage() {
+    eprintln!("{}", USAGE);
+}
+
+fn main() -> Result<ExitCode, io::Error> {
+    let mut dump = false;
+    let mut trace = false;
+    let mut path = None;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--dump-bytecode" => dump = true,
+            "--trace" => trace = true,
+            "--version" => {
+                println!("rlox {}", env!("CARGO_PKG_VERSION"));
+                return Ok(ExitCode::SUCCESS);
+            }
+            "--help" => {
+                println!("{}", USAGE);
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                print_usage();
+                return Ok(ExitCode::from(EX_USAGE));
+            }
         }
     }
+
+    match path {
+        Some(path) if dump => dump_bytecode(&path),
+        Some(path) => run_file(&path, trace),
+        None if dump => {
+            print_usage();
+            Ok(ExitCode::from(EX_USAGE))
+        }
+        None => repl(trace).map(|()| ExitCode::SUCCESS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_is_complete_once_brackets_and_strings_close() {
+        assert!(is_complete("print 1;\n"));
+        assert!(!is_complete("fun f() {\n"));
+        assert!(!is_complete("print [1, (2\n"));
+        assert!(!is_complete("print \"abc\n"));
+        assert!(is_complete("print \"{\";\n"));
+        assert!(is_complete("print 1; // {\n"));
+    }
+
+    #[test]
+    fn triple_quoted_strings_may_contain_quotes() {
+        assert!(is_complete("print \"\"\"it\"s\"\"\";\n"));
+        assert!(!is_complete("print \"\"\"it\"s\n"));
+        assert!(is_complete("print \"\"\"it\"s\nover\"\"\";\n"));
+    }
 }
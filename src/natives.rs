@@ -0,0 +1,49 @@
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Instant;
+use crate::value::Value;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+// Starts the clock read by `clock()`, later calls are no-ops
+pub fn start_clock() {
+    START.get_or_init(Instant::now);
+}
+
+pub fn clock(args: &[Value]) -> Result<Value, String> {
+    if !args.is_empty() {
+        return Err(format!("Expected 0 arguments but got {}.", args.len()));
+    }
+
+    let start = START.get_or_init(Instant::now);
+    Ok(Value::Number(start.elapsed().as_secs_f64()))
+}
+
+pub fn assert(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [condition] if condition.is_falsey() => Err("Assertion failed.".to_string()),
+        [_] => Ok(Value::Nil),
+        _ => Err(format!("Expected 1 argument but got {}.", args.len())),
+    }
+}
+
+// Strings are never converted implicitly, `"3" + 4` is an error, so this is the way to parse one
+pub fn num(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [value @ (Value::Int(_) | Value::Number(_))] => Ok(value.clone()),
+        // Parsed like a number literal, an integer unless it has a fractional part
+        [Value::Str(string)] => string.parse::<i64>().map(Value::Int)
+            .or_else(|_| string.parse::<f64>().map(Value::Number))
+            .map_err(|_| format!("Can't convert '{}' to a number.", string)),
+        [value] => Err(format!("Can't convert {} to a number.", value)),
+        _ => Err(format!("Expected 1 argument but got {}.", args.len())),
+    }
+}
+
+pub fn str(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [value @ Value::Str(_)] => Ok(value.clone()),
+        [value] => Ok(Value::Str(Rc::new(value.to_string()))),
+        _ => Err(format!("Expected 1 argument but got {}.", args.len())),
+    }
+}
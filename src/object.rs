@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use crate::chunk::{Chunk, VerifyError};
+use crate::value::Value;
+
+// Natives report failures as a message, which becomes a runtime error at the call site
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the top-level script
+    pub name: Option<String>,
+}
+
+impl Function {
+    pub fn new(name: Option<&str>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(Some(name.unwrap_or("<script>"))),
+            name: name.map(str::to_string),
+        }
+    }
+}
+
+impl Function {
+    // Prints this function's chunk, followed by the chunks of every function it declares
+    pub fn disassemble(&self) {
+        self.chunk.disassemble();
+
+        for constant in self.chunk.constants() {
+            if let Value::Function(function) = constant {
+                println!();
+                function.disassemble();
+            }
+        }
+    }
+
+    // Verifies this function's chunk and the chunks of every function it declares
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.chunk.verify()?;
+
+        for constant in self.chunk.constants() {
+            if let Value::Function(function) = constant {
+                function.verify()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// A function together with the variables it captured from its enclosing functions
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>) -> Self {
+        Self {
+            upvalues: Vec::with_capacity(function.upvalue_count),
+            function,
+        }
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl fmt::Debug for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    // Fields can be added to an instance at any time, even through a shared reference
+    pub fields: RefCell<HashMap<String, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Self {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// A method closure bound to the instance it was accessed on
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+impl fmt::Debug for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    // The captured variable still lives on the stack, at this index
+    Open(usize),
+    // The variable went out of scope, so the upvalue now owns it
+    Closed(Value),
+}
@@ -1,57 +1,116 @@
-use std::iter::Peekable;
-use std::str::Chars;
 use thiserror::Error;
 
 pub struct Scanner<'a> {
-    current: Peekable<Chars<'a>>,
+    source: &'a str,
     line: usize,
+    // Line the most recently scanned token starts on
+    token_line: usize,
+    // 1-based column of the next character
+    column: usize,
+    // Column the most recently scanned token starts on
+    token_column: usize,
+    // Byte offset of the next character
     position: usize,
+    // Longest identifier or number literal accepted, in bytes
+    max_token_length: usize,
 }
 
 #[derive(Error, Debug)]
 pub enum ScannerError {
-    #[error("Unrecognized character")]
-    UnrecognizedCharacter,
+    #[error("Unrecognized character '{ch}' at line {line}, column {column}")]
+    UnrecognizedCharacter { ch: char, line: usize, column: usize },
 
-    #[error("Unterminated string")]
-    UnterminatedString,
+    #[error("Unterminated string at line {line}, column {column}")]
+    UnterminatedString { line: usize, column: usize },
+
+    #[error("Identifier or number too long at line {line}, column {column}")]
+    TokenTooLong { line: usize, column: usize },
+
+    #[error("Malformed number literal '{literal}' at line {line}, column {column}")]
+    MalformedNumber { literal: String, line: usize, column: usize },
 }
 
-#[derive(Debug)]
-pub enum KeywordKind {
-    And,
-    Class,
-    Else,
-    False,
-    For,
-    Fun,
-    If,
-    Nil,
-    Or,
-    Print,
-    Return,
-    Super,
-    This,
-    True,
-    Var,
-    While,
+impl ScannerError {
+    // The (line, column) of the offending character, or the start of the offending token
+    pub fn location(&self) -> (usize, usize) {
+        match *self {
+            ScannerError::UnrecognizedCharacter { line, column, .. }
+            | ScannerError::UnterminatedString { line, column }
+            | ScannerError::TokenTooLong { line, column }
+            | ScannerError::MalformedNumber { line, column, .. } => (line, column),
+        }
+    }
 }
 
-// All tokens return their starting column position
-#[derive(Debug)]
+// Defines `KeywordKind` together with its spelling, so a new keyword can never be missing from
+// `ALL` or either conversion
+macro_rules! keywords {
+    ($($name:ident => $text:literal),* $(,)?) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum KeywordKind {
+            $($name),*
+        }
+
+        impl KeywordKind {
+            pub const ALL: [KeywordKind; [$($text),*].len()] = [$(KeywordKind::$name),*];
+
+            // The keyword's spelling in source code
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(KeywordKind::$name => $text),*
+                }
+            }
+
+            // The keyword spelled `s`, or None for any other identifier. Not `FromStr`, since a
+            // non-keyword isn't an error.
+            #[allow(clippy::should_implement_trait)]
+            pub fn from_str(s: &str) -> Option<KeywordKind> {
+                match s {
+                    $($text => Some(KeywordKind::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+keywords! {
+    And => "and",
+    Break => "break",
+    Class => "class",
+    Continue => "continue",
+    Else => "else",
+    False => "false",
+    For => "for",
+    Fun => "fun",
+    If => "if",
+    Nil => "nil",
+    Or => "or",
+    Print => "print",
+    Return => "return",
+    Super => "super",
+    This => "this",
+    True => "true",
+    Var => "var",
+    While => "while",
+}
+
+// All tokens return their starting byte offset in the source
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     // Single character tokens
     LeftParen(usize),
     RightParen(usize),
     LeftBrace(usize),
     RightBrace(usize),
+    LeftBracket(usize),
+    RightBracket(usize),
     Comma(usize),
     Dot(usize),
-    Minus(usize),
-    Plus(usize),
     Semicolon(usize),
-    Slash(usize),
-    Star(usize),
+    Question(usize),
+    Colon(usize),
 
     // One or two character tokens
     Bang(usize),
@@ -62,6 +121,16 @@ pub enum Token {
     GreaterEqual(usize),
     Less(usize),
     LessEqual(usize),
+    Minus(usize),
+    MinusEqual(usize),
+    Plus(usize),
+    PlusEqual(usize),
+    Slash(usize),
+    SlashEqual(usize),
+    Star(usize),
+    StarEqual(usize),
+    Percent(usize),
+    PercentEqual(usize),
 
     // Literals
     Identifier(usize, usize),
@@ -69,83 +138,257 @@ pub enum Token {
     Number(usize, usize),
     Keyword(usize, KeywordKind),
 
+    #[allow(dead_code)]
     Error(usize),
     EOF(usize),
 }
 
+impl Token {
+    // Byte offset of the token's first character
+    pub fn start(&self) -> usize {
+        match *self {
+            Token::Identifier(start, _)
+            | Token::String(start, _)
+            | Token::Number(start, _)
+            | Token::Keyword(start, _)
+            | Token::Error(start)
+            | Token::EOF(start)
+            | Token::BangEqual(start)
+            | Token::EqualEqual(start)
+            | Token::GreaterEqual(start)
+            | Token::LessEqual(start)
+            | Token::MinusEqual(start)
+            | Token::PlusEqual(start)
+            | Token::SlashEqual(start)
+            | Token::StarEqual(start)
+            | Token::PercentEqual(start)
+            | Token::Percent(start)
+            | Token::LeftParen(start)
+            | Token::RightParen(start)
+            | Token::LeftBrace(start)
+            | Token::RightBrace(start)
+            | Token::LeftBracket(start)
+            | Token::RightBracket(start)
+            | Token::Comma(start)
+            | Token::Dot(start)
+            | Token::Minus(start)
+            | Token::Plus(start)
+            | Token::Semicolon(start)
+            | Token::Slash(start)
+            | Token::Star(start)
+            | Token::Question(start)
+            | Token::Colon(start)
+            | Token::Bang(start)
+            | Token::Equal(start)
+            | Token::Greater(start)
+            | Token::Less(start) => start,
+        }
+    }
+
+    // The text of the token, given the source it was scanned from
+    pub fn lexeme<'s>(&self, source: &'s str) -> &'s str {
+        match *self {
+            Token::Identifier(start, end)
+            | Token::String(start, end)
+            | Token::Number(start, end) => &source[start..end],
+            Token::EOF(_) => "",
+            Token::Keyword(start, _) | Token::Error(start) => {
+                let length = source[start..]
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(source.len() - start);
+
+                &source[start..start + length]
+            }
+            Token::BangEqual(start)
+            | Token::EqualEqual(start)
+            | Token::GreaterEqual(start)
+            | Token::LessEqual(start)
+            | Token::MinusEqual(start)
+            | Token::PlusEqual(start)
+            | Token::SlashEqual(start)
+            | Token::StarEqual(start)
+            | Token::PercentEqual(start) => &source[start..start + 2],
+            Token::LeftParen(start)
+            | Token::RightParen(start)
+            | Token::LeftBrace(start)
+            | Token::RightBrace(start)
+            | Token::LeftBracket(start)
+            | Token::RightBracket(start)
+            | Token::Comma(start)
+            | Token::Dot(start)
+            | Token::Minus(start)
+            | Token::Plus(start)
+            | Token::Semicolon(start)
+            | Token::Slash(start)
+            | Token::Star(start)
+            | Token::Percent(start)
+            | Token::Question(start)
+            | Token::Colon(start)
+            | Token::Bang(start)
+            | Token::Equal(start)
+            | Token::Greater(start)
+            | Token::Less(start) => &source[start..start + 1],
+        }
+    }
+}
+
+// Scans all of `source` into tokens ending with EOF, or returns the first error found
+pub fn scan_tokens(source: &str) -> Result<Vec<Token>, ScannerError> {
+    let (tokens, errors) = Scanner::new(source).scan_all();
+
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(tokens),
+    }
+}
+
 macro_rules! scan_two_char_operator {
-    ($self:expr, $one_result:expr, $two_result:expr) => {
-        if let Some(c) = $self.current.peek() {
-            if *c == '=' {
+    ($self:expr, $start:expr, $one_result:expr, $two_result:expr) => {
+        if let Some(c) = $self.peek() {
+            if c == '=' {
                 $self.advance();
-                return Ok($two_result($self.position));
+                Ok($two_result($start))
             } else {
-                return Ok($one_result($self.position));
+                Ok($one_result($start))
             }
         } else {
-            return Ok($one_result($self.position));
+            Ok($one_result($start))
         }
     };
 }
 
 impl<'a, 'outlives_a: 'a> Scanner<'a> {
+    pub const MAX_TOKEN_LENGTH: usize = 4096;
+
     pub fn new(source: &'outlives_a str) -> Self {
         Self {
-            current: source.chars().peekable(),
+            source,
             line: 1,
-            position: 1,
+            token_line: 1,
+            column: 1,
+            token_column: 1,
+            position: 0,
+            max_token_length: Self::MAX_TOKEN_LENGTH,
         }
     }
 
-    pub fn scan_token(&'a mut self) -> Result<Token, ScannerError> {
+    pub fn set_max_token_length(&mut self, max_token_length: usize) {
+        self.max_token_length = max_token_length;
+    }
+
+    // Starts scanning `source` from the beginning, reusing this scanner
+    pub fn reset(&mut self, source: &'outlives_a str) {
+        self.source = source;
+        self.line = 1;
+        self.token_line = 1;
+        self.column = 1;
+        self.token_column = 1;
+        self.position = 0;
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    // 1-based line the token returned by the last `scan_token` call starts on
+    pub fn token_line(&self) -> usize {
+        self.token_line
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    pub fn scan_token(&mut self) -> Result<Token, ScannerError> {
+        self.skip_whitespace();
+
+        let start = self.position;
+        self.token_line = self.line;
+        self.token_column = self.column;
         if let Some(c) = self.advance() {
             return match c {
-                '(' => Ok(Token::LeftParen(self.position)),
-                ')' => Ok(Token::RightParen(self.position)),
-                '{' => Ok(Token::LeftBrace(self.position)),
-                '}' => Ok(Token::RightBrace(self.position)),
-                ';' => Ok(Token::Semicolon(self.position)),
-                ',' => Ok(Token::Comma(self.position)),
-                '.' => Ok(Token::Dot(self.position)),
-                '-' => Ok(Token::Minus(self.position)),
-                '+' => Ok(Token::Plus(self.position)),
-                '/' => Ok(Token::Slash(self.position)),
-                '*' => Ok(Token::Star(self.position)),
-                '!' => scan_two_char_operator!(self, Token::Bang, Token::BangEqual),
-                '=' => scan_two_char_operator!(self, Token::Equal, Token::EqualEqual),
-                '<' => scan_two_char_operator!(self, Token::Less, Token::LessEqual),
-                '>' => scan_two_char_operator!(self, Token::Greater, Token::GreaterEqual),
-                '"' => self.scan_string(),
-                c if c.is_ascii_digit() || c == '_' => self.scan_number(),
-                c if c.is_ascii_alphabetic() => self.scan_identifier(),
-                _ => return Err(ScannerError::UnrecognizedCharacter)
+                '(' => Ok(Token::LeftParen(start)),
+                ')' => Ok(Token::RightParen(start)),
+                '{' => Ok(Token::LeftBrace(start)),
+                '}' => Ok(Token::RightBrace(start)),
+                '[' => Ok(Token::LeftBracket(start)),
+                ']' => Ok(Token::RightBracket(start)),
+                ';' => Ok(Token::Semicolon(start)),
+                ',' => Ok(Token::Comma(start)),
+                '.' => Ok(Token::Dot(start)),
+                '?' => Ok(Token::Question(start)),
+                ':' => Ok(Token::Colon(start)),
+                '!' => scan_two_char_operator!(self, start, Token::Bang, Token::BangEqual),
+                '=' => scan_two_char_operator!(self, start, Token::Equal, Token::EqualEqual),
+                '<' => scan_two_char_operator!(self, start, Token::Less, Token::LessEqual),
+                '>' => scan_two_char_operator!(self, start, Token::Greater, Token::GreaterEqual),
+                '-' => scan_two_char_operator!(self, start, Token::Minus, Token::MinusEqual),
+                '+' => scan_two_char_operator!(self, start, Token::Plus, Token::PlusEqual),
+                '/' => scan_two_char_operator!(self, start, Token::Slash, Token::SlashEqual),
+                '*' => scan_two_char_operator!(self, start, Token::Star, Token::StarEqual),
+                '%' => scan_two_char_operator!(self, start, Token::Percent, Token::PercentEqual),
+                '"' => self.scan_string(start),
+                c if c.is_ascii_digit() => self.scan_number(start),
+                // Identifiers may be non-ASCII, keywords are matched exactly and stay ASCII
+                c if c.is_alphabetic() || c == '_' => self.scan_identifier(start, c),
+                ch => Err(ScannerError::UnrecognizedCharacter {
+                    ch,
+                    line: self.token_line,
+                    column: self.token_column,
+                }),
             };
         }
 
-        if self.current.peek().is_none() {
-            return Ok(Token::EOF(self.position));
+        Ok(Token::EOF(self.position))
+    }
+
+    // Scans the whole source, skipping over bad characters so every error is reported at once.
+    // The tokens always end with EOF.
+    pub fn scan_all(&mut self) -> (Vec<Token>, Vec<ScannerError>) {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+
+        loop {
+            match self.scan_token() {
+                Ok(token @ Token::EOF(_)) => {
+                    tokens.push(token);
+                    break;
+                }
+                Ok(token) => tokens.push(token),
+                // The offending character has already been consumed
+                Err(err) => errors.push(err),
+            }
         }
 
-        return Err(ScannerError::UnrecognizedCharacter);
+        (tokens, errors)
     }
 
     fn is_at_end(&self) -> bool {
-        self.current.clone().peek().is_some()
+        self.lookahead(0).is_none()
     }
 
     fn advance(&mut self) -> Option<char> {
-        self.position += 1;
-        self.current.next()
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(c)
     }
 
-    fn peek(&mut self) -> Option<&char> {
-        self.current.peek()
+    fn peek(&self) -> Option<char> {
+        self.lookahead(0)
     }
 
-    fn peek_next(&mut self) -> Option<char> {
-        let mut peeked_iter = self.current.clone();
-        peeked_iter.next();
-        peeked_iter.next()
+    // The character `n` places after the next one, without consuming anything.
+    // `lookahead(0)` is the next character.
+    fn lookahead(&self, n: usize) -> Option<char> {
+        self.source[self.position..].chars().nth(n)
     }
 
     fn skip_whitespace(&mut self) {
@@ -153,16 +396,14 @@ impl<'a, 'outlives_a: 'a> Scanner<'a> {
             if c.is_whitespace() || c.is_ascii_whitespace() {
                 // Skip whitespace
                 self.advance();
-            } else if *c == '\n' {
-                // Skip newline
-                self.line += 1;
-                self.advance();
-            } else if *c == '/' {
-                if self.peek_next().is_some_and(|c| c == '/') {
+            } else if c == '/' {
+                if self.lookahead(1).is_some_and(|c| c == '/') {
                     // Skip entire comment line
-                    while self.peek().is_some_and(|c| *c != '\n') && !self.is_at_end() {
+                    while self.peek().is_some_and(|c| c != '\n') {
                         self.advance();
                     }
+                } else {
+                    break;
                 }
             } else {
                 break;
@@ -170,19 +411,17 @@ impl<'a, 'outlives_a: 'a> Scanner<'a> {
         }
     }
 
-    fn scan_string(&'a mut self) -> Result<Token, ScannerError> {
-        let start_position = self.position;
-
-        while self.peek().is_some_and(|c| *c != '"') && !self.is_at_end() {
-            if self.peek().is_some_and(|c| *c == '\n') {
-                self.line += 1;
-            }
+    fn scan_string(&mut self, start_position: usize) -> Result<Token, ScannerError> {
+        if self.lookahead(0) == Some('"') && self.lookahead(1) == Some('"') {
+            return self.scan_triple_quoted_string(start_position);
+        }
 
+        while self.peek().is_some_and(|c| c != '"') {
             self.advance();
         }
 
         if self.is_at_end() {
-            return Err(ScannerError::UnterminatedString);
+            return Err(self.unterminated_string());
         }
 
         // Consume closing quote
@@ -191,15 +430,47 @@ impl<'a, 'outlives_a: 'a> Scanner<'a> {
         Ok(Token::String(start_position, self.position))
     }
 
-    fn scan_number(&mut self) -> Result<Token, ScannerError> {
-        let start_position = self.position;
+    fn unterminated_string(&self) -> ScannerError {
+        ScannerError::UnterminatedString { line: self.token_line, column: self.token_column }
+    }
+
+    // Scans a string delimited by three quotes, which may contain single and double quotes.
+    // The token includes the delimiters.
+    fn scan_triple_quoted_string(&mut self, start_position: usize) -> Result<Token, ScannerError> {
+        // Consume the rest of the opening delimiter
+        self.advance();
+        self.advance();
+
+        loop {
+            match self.advance() {
+                None => return Err(self.unterminated_string()),
+                Some('"') if self.lookahead(0) == Some('"') && self.lookahead(1) == Some('"') => {
+                    self.advance();
+                    self.advance();
+
+                    return Ok(Token::String(start_position, self.position));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn scan_number(&mut self, start_position: usize) -> Result<Token, ScannerError> {
+        let radix = match self.peek() {
+            Some('x' | 'X') => Some(16),
+            Some('b' | 'B') => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix.filter(|_| &self.source[start_position..self.position] == "0") {
+            return self.scan_radix_number(start_position, radix);
+        }
 
         while self.peek().is_some_and(|c| c.is_ascii_digit()) {
             self.advance();
         }
 
-        if self.peek().is_some_and(|c| *c == '.')
-            && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+        if self.peek() == Some('.')
+            && self.lookahead(1).is_some_and(|c| c.is_ascii_digit()) {
             self.advance(); // Consume dot
 
             while self.peek().is_some_and(|c| c.is_ascii_digit()) {
@@ -207,38 +478,288 @@ impl<'a, 'outlives_a: 'a> Scanner<'a> {
             }
         }
 
+        self.check_token_length(start_position)?;
+        Ok(Token::Number(start_position, self.position))
+    }
+
+    // Scans a hexadecimal or binary integer, whose leading `0` has already been consumed
+    fn scan_radix_number(&mut self, start_position: usize, radix: u32) -> Result<Token, ScannerError> {
+        self.advance(); // Consume the `x` or `b`
+
+        // Any letters and digits right after belong to the literal, so `0xG` is rejected whole
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.advance();
+        }
+        self.check_token_length(start_position)?;
+
+        let digits = &self.source[start_position + 2..self.position];
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            let literal = self.source[start_position..self.position].to_string();
+            return Err(ScannerError::MalformedNumber {
+                literal,
+                line: self.token_line,
+                column: self.token_column,
+            });
+        }
+
         Ok(Token::Number(start_position, self.position))
     }
 
-    fn scan_identifier(&mut self) -> Result<Token, ScannerError> {
-        let start_position = self.position;
-        let mut buffer = String::new();
+    fn scan_identifier(&mut self, start_position: usize, first: char) -> Result<Token, ScannerError> {
+        let mut buffer = String::from(first);
 
-        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
-            buffer.push(*self.peek().unwrap());
+        while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+            // The whole identifier is still consumed, but only buffered up to the limit
+            if buffer.len() <= self.max_token_length {
+                buffer.push(c);
+            }
             self.advance();
         }
 
-        match buffer.as_str() {
-            "and" => return Ok(Token::Keyword(start_position, KeywordKind::And)),
-            "class" => return Ok(Token::Keyword(start_position, KeywordKind::Class)),
-            "else" => return Ok(Token::Keyword(start_position, KeywordKind::Else)),
-            "false" => return Ok(Token::Keyword(start_position, KeywordKind::False)),
-            "for" => return Ok(Token::Keyword(start_position, KeywordKind::For)),
-            "fun" => return Ok(Token::Keyword(start_position, KeywordKind::Fun)),
-            "if" => return Ok(Token::Keyword(start_position, KeywordKind::If)),
-            "nil" => return Ok(Token::Keyword(start_position, KeywordKind::Nil)),
-            "or" => return Ok(Token::Keyword(start_position, KeywordKind::Or)),
-            "print" => return Ok(Token::Keyword(start_position, KeywordKind::Print)),
-            "return" => return Ok(Token::Keyword(start_position, KeywordKind::Return)),
-            "super" => return Ok(Token::Keyword(start_position, KeywordKind::Super)),
-            "this" => return Ok(Token::Keyword(start_position, KeywordKind::This)),
-            "true" => return Ok(Token::Keyword(start_position, KeywordKind::True)),
-            "var" => return Ok(Token::Keyword(start_position, KeywordKind::Var)),
-            "while" => return Ok(Token::Keyword(start_position, KeywordKind::While)),
-            _ => {}
+        self.check_token_length(start_position)?;
+        if let Some(keyword) = KeywordKind::from_str(&buffer) {
+            return Ok(Token::Keyword(start_position, keyword));
         }
 
         Ok(Token::Identifier(start_position, self.position))
     }
+
+    // Rejects the token that started at `start_position` and was just consumed, if it's too long
+    fn check_token_length(&self, start_position: usize) -> Result<(), ScannerError> {
+        if self.position - start_position > self.max_token_length {
+            return Err(ScannerError::TokenTooLong { line: self.token_line, column: self.token_column });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_report_the_line_they_start_on() {
+        let source = "var a;\n// comment\nprint \"two\nlines\" + a;";
+        let mut scanner = Scanner::new(source);
+
+        let mut lines = Vec::new();
+        loop {
+            let token = scanner.scan_token().unwrap();
+            lines.push((token.lexeme(source), scanner.token_line()));
+            if let Token::EOF(_) = token {
+                break;
+            }
+        }
+
+        assert_eq!(lines, vec![
+            ("var", 1), ("a", 1), (";", 1),
+            ("print", 3), ("\"two\nlines\"", 3), ("+", 4), ("a", 4), (";", 4),
+            ("", 4),
+        ]);
+    }
+
+    #[test]
+    fn radix_literals_scan_as_one_number() {
+        let source = "0xFF 0b1010";
+        let tokens = scan_tokens(source).unwrap();
+        let lexemes: Vec<_> = tokens.iter().map(|token| token.lexeme(source)).collect();
+
+        assert!(matches!(tokens[..], [Token::Number(..), Token::Number(..), Token::EOF(_)]));
+        assert_eq!(lexemes, ["0xFF", "0b1010", ""]);
+    }
+
+    #[test]
+    fn malformed_radix_literal_is_rejected_whole() {
+        let (tokens, errors) = Scanner::new("0xG;").scan_all();
+
+        assert!(matches!(tokens[..], [Token::Semicolon(3), Token::EOF(4)]));
+        assert!(matches!(&errors[..], [ScannerError::MalformedNumber { literal, .. }] if literal == "0xG"));
+    }
+
+    #[test]
+    fn unrecognized_character_reports_where_it_is() {
+        let err = Scanner::new("var a;\n  @").scan_all().1.remove(0);
+
+        assert!(matches!(err, ScannerError::UnrecognizedCharacter { ch: '@', line: 2, column: 3 }));
+        assert_eq!(err.to_string(), "Unrecognized character '@' at line 2, column 3");
+    }
+
+    #[test]
+    fn every_bad_character_is_reported() {
+        let (tokens, errors) = Scanner::new("@ 1 $").scan_all();
+
+        assert!(matches!(tokens[..], [Token::Number(2, 3), Token::EOF(5)]));
+        assert!(matches!(errors[..], [
+            ScannerError::UnrecognizedCharacter { ch: '@', column: 1, .. },
+            ScannerError::UnrecognizedCharacter { ch: '$', column: 5, .. },
+        ]));
+    }
+
+    #[test]
+    fn lookahead_sees_characters_without_consuming_them() {
+        let mut scanner = Scanner::new("ab");
+        assert_eq!((scanner.lookahead(0), scanner.lookahead(1), scanner.lookahead(2)), (Some('a'), Some('b'), None));
+
+        scanner.advance();
+        assert_eq!((scanner.lookahead(0), scanner.lookahead(1), scanner.lookahead(2)), (Some('b'), None, None));
+
+        scanner.advance();
+        assert_eq!((scanner.lookahead(0), scanner.lookahead(1), scanner.lookahead(2)), (None, None, None));
+        assert!(scanner.is_at_end());
+    }
+
+    #[test]
+    fn lookahead_steps_over_multi_byte_characters() {
+        let mut scanner = Scanner::new("é!");
+        assert_eq!((scanner.lookahead(0), scanner.lookahead(1)), (Some('é'), Some('!')));
+
+        scanner.advance();
+        assert_eq!(scanner.position, 'é'.len_utf8());
+        assert_eq!((scanner.lookahead(0), scanner.lookahead(1)), (Some('!'), None));
+    }
+
+    #[test]
+    fn two_character_lookahead_near_the_end() {
+        let tokens = |source| Scanner::new(source).scan_all().0;
+
+        // A trailing dot isn't part of the number
+        assert!(matches!(tokens("1.")[..], [Token::Number(0, 1), Token::Dot(1), Token::EOF(2)]));
+        assert!(matches!(tokens("1.5")[..], [Token::Number(0, 3), Token::EOF(3)]));
+        assert!(matches!(tokens("/")[..], [Token::Slash(0), Token::EOF(1)]));
+        assert!(matches!(tokens("//")[..], [Token::EOF(2)]));
+        assert!(matches!(tokens(">=")[..], [Token::GreaterEqual(0), Token::EOF(2)]));
+    }
+
+    #[test]
+    fn tokens_give_back_their_lexeme() {
+        let source = "var answer = 42.5;";
+        let tokens = scan_tokens(source).unwrap();
+
+        assert!(matches!(tokens[1], Token::Identifier(..)));
+        assert_eq!(tokens[1].lexeme(source), "answer");
+        assert!(matches!(tokens[3], Token::Number(..)));
+        assert_eq!(tokens[3].lexeme(source), "42.5");
+    }
+
+    #[test]
+    fn reset_scanner_scans_a_new_source_from_the_start() {
+        let mut scanner = Scanner::new("var a;\nprint a;");
+        scanner.scan_all();
+        assert_eq!(scanner.line(), 2);
+
+        let source = "1 + 2";
+        scanner.reset(source);
+        let (tokens, errors) = scanner.scan_all();
+
+        assert!(errors.is_empty());
+        assert!(matches!(tokens[..], [Token::Number(0, 1), Token::Plus(2), Token::Number(4, 5), Token::EOF(5)]));
+        assert_eq!(scanner.line(), 1);
+    }
+
+    #[test]
+    fn string_literal_ends_at_its_closing_quote() {
+        let source = "\"hi\";";
+        let tokens = scan_tokens(source).unwrap();
+
+        assert!(matches!(tokens[..], [Token::String(0, 4), Token::Semicolon(4), Token::EOF(5)]));
+        assert_eq!(tokens[0].lexeme(source), "\"hi\"");
+    }
+
+    #[test]
+    fn keywords_round_trip_through_their_text() {
+        for keyword in KeywordKind::ALL {
+            assert_eq!(KeywordKind::from_str(keyword.as_str()), Some(keyword));
+        }
+
+        assert_eq!(KeywordKind::from_str("nope"), None);
+        assert_eq!(KeywordKind::from_str("Print"), None);
+    }
+
+    #[test]
+    fn all_lists_every_keyword() {
+        // No wildcard arm, so a new keyword doesn't compile until it's spelled out here
+        let spelling = |keyword| match keyword {
+            KeywordKind::And => "and",
+            KeywordKind::Break => "break",
+            KeywordKind::Class => "class",
+            KeywordKind::Continue => "continue",
+            KeywordKind::Else => "else",
+            KeywordKind::False => "false",
+            KeywordKind::For => "for",
+            KeywordKind::Fun => "fun",
+            KeywordKind::If => "if",
+            KeywordKind::Nil => "nil",
+            KeywordKind::Or => "or",
+            KeywordKind::Print => "print",
+            KeywordKind::Return => "return",
+            KeywordKind::Super => "super",
+            KeywordKind::This => "this",
+            KeywordKind::True => "true",
+            KeywordKind::Var => "var",
+            KeywordKind::While => "while",
+        };
+
+        for keyword in KeywordKind::ALL {
+            assert_eq!(keyword.as_str(), spelling(keyword));
+        }
+        // One per arm above
+        assert_eq!(KeywordKind::ALL.len(), 18);
+    }
+
+    #[test]
+    fn brackets_and_commas_scan_as_their_own_tokens() {
+        let tokens = scan_tokens("[1,2]").unwrap();
+
+        assert!(matches!(tokens[..], [
+            Token::LeftBracket(0), Token::Number(1, 2), Token::Comma(2), Token::Number(3, 4), Token::RightBracket(4),
+            Token::EOF(5),
+        ]));
+    }
+
+    #[test]
+    fn comment_runs_to_the_end_of_the_source() {
+        let tokens = scan_tokens("1 // trailing").unwrap();
+
+        assert!(matches!(tokens[..], [Token::Number(0, 1), Token::EOF(13)]));
+    }
+
+    #[test]
+    fn identifiers_can_be_non_ascii() {
+        let source = "var café = 变量;";
+        let tokens = scan_tokens(source).unwrap();
+
+        assert!(matches!(tokens[1], Token::Identifier(..)));
+        assert_eq!(tokens[1].lexeme(source), "café");
+        assert!(matches!(tokens[3], Token::Identifier(..)));
+        assert_eq!(tokens[3].lexeme(source), "变量");
+
+        // Columns count characters, not bytes
+        let err = Scanner::new("café @").scan_all().1.remove(0);
+        assert!(matches!(err, ScannerError::UnrecognizedCharacter { ch: '@', line: 1, column: 6 }));
+    }
+
+    #[test]
+    fn over_long_identifier_is_rejected() {
+        let mut scanner = Scanner::new("var abcdef;\nabcde;");
+        scanner.set_max_token_length(5);
+        let (tokens, errors) = scanner.scan_all();
+
+        // Only the identifier over the limit is dropped
+        assert!(matches!(errors[..], [ScannerError::TokenTooLong { line: 1, column: 5 }]));
+        assert!(matches!(tokens[..], [
+            Token::Keyword(0, KeywordKind::Var), Token::Semicolon(10),
+            Token::Identifier(12, 17), Token::Semicolon(17), Token::EOF(18),
+        ]));
+    }
+
+    #[test]
+    fn scan_tokens_returns_every_token_up_to_eof() {
+        let tokens = scan_tokens("print \"hi\";").unwrap();
+
+        assert!(matches!(tokens[..], [
+            Token::Keyword(0, KeywordKind::Print), Token::String(6, 10), Token::Semicolon(10), Token::EOF(11),
+        ]));
+        assert!(matches!(scan_tokens("print \"hi"), Err(ScannerError::UnterminatedString { line: 1, column: 7 })));
+    }
 }
@@ -0,0 +1,76 @@
+use std::ops::{Index, IndexMut, RangeFrom};
+use crate::value::Value;
+
+// The VM's value stack, allocated once at its maximum size. A `Vec` rather than a fixed array of
+// slots, since popping moves values out and leaves nothing behind to keep objects alive.
+pub struct Stack {
+    values: Vec<Value>,
+    capacity: usize,
+}
+
+impl Stack {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    // The caller checks for overflow, so it can be reported as a runtime error
+    #[inline]
+    pub fn push(&mut self, value: Value) {
+        debug_assert!(self.values.len() < self.capacity, "stack overflow");
+        self.values.push(value);
+    }
+
+    #[inline]
+    pub fn pop(&mut self) -> Option<Value> {
+        self.values.pop()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn as_slice(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Value> {
+        self.as_slice().iter()
+    }
+}
+
+impl Index<usize> for Stack {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        &self.values[index]
+    }
+}
+
+impl IndexMut<usize> for Stack {
+    fn index_mut(&mut self, index: usize) -> &mut Value {
+        &mut self.values[index]
+    }
+}
+
+impl Index<RangeFrom<usize>> for Stack {
+    type Output = [Value];
+
+    fn index(&self, range: RangeFrom<usize>) -> &[Value] {
+        &self.as_slice()[range]
+    }
+}
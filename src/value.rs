@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, NativeFn};
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(f64),
+    Str(Rc<String>),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    NativeFn(NativeFn),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // Numeric value of either kind of number, integers are promoted to floating point
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Int(value) => Some(*value as f64),
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => *a as f64 == *b,
+            // Strings are interned, so equal strings are always the same allocation
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            // Objects are only equal to themselves
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::NativeFn(a), Value::NativeFn(b)) => std::ptr::fn_addr_eq(*a, *b),
+            _ => false,
+        }
+    }
+}
+
+// Wraps a value so it can key a hash map. Stricter than `Value`'s equality, which follows Lox's
+// `==`: numbers are keyed by their bit pattern, so NaN finds itself again and 1 and 1.0 are
+// different keys, with 0.0 and -0.0 the same key. Strings are keyed by allocation, so equal
+// text is only the same key when both strings come from the same interner. Objects are keyed
+// by identity.
+#[derive(Clone, Debug)]
+pub struct ValueKey(pub Value);
+
+impl ValueKey {
+    // Adding 0.0 turns -0.0 into 0.0 and leaves every other value, NaN included, as it is
+    fn number_bits(value: f64) -> u64 {
+        (value + 0.0).to_bits()
+    }
+}
+
+impl PartialEq for ValueKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => Self::number_bits(*a) == Self::number_bits(*b),
+            (Value::Int(_), Value::Number(_)) | (Value::Number(_), Value::Int(_)) => false,
+            // Every other kind already compares by value or identity
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for ValueKey {}
+
+impl Hash for ValueKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(&self.0).hash(state);
+
+        match &self.0 {
+            Value::Nil => {}
+            Value::Bool(value) => value.hash(state),
+            Value::Int(value) => value.hash(state),
+            Value::Number(value) => Self::number_bits(*value).hash(state),
+            Value::Str(value) => Rc::as_ptr(value).hash(state),
+            Value::Function(value) => Rc::as_ptr(value).hash(state),
+            Value::Closure(value) => Rc::as_ptr(value).hash(state),
+            Value::Class(value) => Rc::as_ptr(value).hash(state),
+            Value::Instance(value) => Rc::as_ptr(value).hash(state),
+            Value::BoundMethod(value) => Rc::as_ptr(value).hash(state),
+            Value::List(value) => Rc::as_ptr(value).hash(state),
+            Value::NativeFn(value) => value.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            // Integral numbers print without a fractional part, and NaN is spelled like clox does
+            Value::Number(value) if value.is_nan() => write!(f, "nan"),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{}", value),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Closure(closure) => write!(f, "{}", closure),
+            Value::Class(class) => write!(f, "{}", class),
+            Value::Instance(instance) => write!(f, "{}", instance),
+            Value::BoundMethod(bound_method) => write!(f, "{}", bound_method),
+            Value::List(list) => {
+                write!(f, "[")?;
+                for (index, element) in list.borrow().iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Value::NativeFn(_) => write!(f, "<native fn>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::interner::Interner;
+
+    // Objects are keyed by identity, so mutating one never changes its hash
+    #[allow(clippy::mutable_key_type)]
+    #[test]
+    fn numbers_and_strings_key_a_hash_map() {
+        let mut interner = Interner::new();
+        let mut map = HashMap::new();
+        map.insert(ValueKey(Value::Int(1)), "int");
+        map.insert(ValueKey(Value::Number(1.0)), "float");
+        map.insert(ValueKey(Value::Number(f64::NAN)), "nan");
+        map.insert(ValueKey(Value::Number(-0.0)), "zero");
+        map.insert(ValueKey(Value::Str(interner.intern("key"))), "string");
+        map.insert(ValueKey(Value::Nil), "nil");
+
+        assert_eq!(map[&ValueKey(Value::Int(1))], "int");
+        assert_eq!(map[&ValueKey(Value::Number(1.0))], "float");
+        assert_eq!(map[&ValueKey(Value::Number(f64::NAN))], "nan");
+        assert_eq!(map[&ValueKey(Value::Number(0.0))], "zero");
+        assert_eq!(map[&ValueKey(Value::Str(interner.intern("key")))], "string");
+        assert_eq!(map[&ValueKey(Value::Nil)], "nil");
+        assert!(!map.contains_key(&ValueKey(Value::Bool(false))));
+    }
+
+    #[test]
+    fn values_display_like_lox_prints_them() {
+        let mut interner = Interner::new();
+        let class = Rc::new(Class::new("Point"));
+        let function = Rc::new(Function::new(Some("area")));
+        let closure = Rc::new(Closure::new(function.clone()));
+        let list = Value::List(Rc::new(RefCell::new(vec![Value::Int(1), Value::Str(interner.intern("a"))])));
+
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Int(-3).to_string(), "-3");
+        assert_eq!(Value::Number(2.0).to_string(), "2");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "nan");
+        assert_eq!(Value::Str(interner.intern("text")).to_string(), "text");
+        assert_eq!(Value::Function(Rc::new(Function::new(None))).to_string(), "<script>");
+        assert_eq!(Value::Function(function).to_string(), "<fn area>");
+        assert_eq!(Value::Closure(closure.clone()).to_string(), "<fn area>");
+        assert_eq!(Value::Class(class.clone()).to_string(), "Point");
+        assert_eq!(Value::Instance(Rc::new(Instance::new(class.clone()))).to_string(), "Point instance");
+        let receiver = Value::Instance(Rc::new(Instance::new(class)));
+        assert_eq!(Value::BoundMethod(Rc::new(BoundMethod { receiver, method: closure })).to_string(), "<fn area>");
+        assert_eq!(list.to_string(), "[1, a]");
+        assert_eq!(Value::NativeFn(|_| Ok(Value::Nil)).to_string(), "<native fn>");
+    }
+}
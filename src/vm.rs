@@ -1,107 +1,1764 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 use thiserror::Error;
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{OpCode, VerifyError};
 use crate::compiler::{CompileError, Compiler};
+use crate::gc::Heap;
+use crate::interner::Interner;
+use crate::natives;
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, NativeFn, Upvalue};
+use crate::stack::Stack;
+use crate::value::Value;
 
-pub struct VM<'a> {
-    compiler: Compiler<'a>,
+pub struct VM {
+    frames: Vec<CallFrame>,
+    // The running frame's function and instruction pointer, kept outside `frames` so reading a
+    // byte doesn't go through the frame and its closure
+    function: Rc<Function>,
     ip: usize,
-    stack: Vec<f32>
+    stack: Stack,
+    globals: HashMap<Rc<String>, Value>,
+    // Upvalues still pointing into the stack, so closures capturing the same variable share it
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    heap: Heap,
+    strings: Rc<RefCell<Interner>>,
+    // Print the stack and the current instruction before executing it
+    trace: bool,
+    // Verify compiled bytecode before running it
+    verify: bool,
+    // Whether `print` statements in loaded programs end with a newline
+    print_newline: bool,
+    // Whether loaded programs may end with an expression without a `;`, which becomes their result
+    result_expression: bool,
+    // Instructions a single program may execute before it's stopped, if limited
+    instruction_limit: Option<u64>,
+    // Instructions executed since the program was loaded
+    instruction_count: u64,
+    // Count how many times each opcode executes
+    profile: bool,
+    // Run instructions through `DISPATCH` instead of matching on the opcode
+    #[cfg(feature = "fn-dispatch")]
+    fn_dispatch: bool,
+    // Executions per opcode since the program was loaded, only tallied when profiling
+    opcode_counts: HashMap<OpCode, u64>,
+    // Where `print` and script results are written
+    output: Box<dyn Write>,
+    // Line of the return that ended the last program, for errors reported after it halted
+    result_line: usize,
 }
 
-enum BinaryOp {
-    Addition, Subtraction, Multiplication, Division
+pub enum StepResult {
+    Continue,
+    // The script returned, with its result
+    Halted(Value),
+}
+
+struct CallFrame {
+    closure: Rc<Closure>,
+    // Where the frame resumes once its callee returns, the running frame's is `VM::ip`
+    ip: usize,
+    // Index of the frame's first stack slot, which holds the called function
+    slots: usize,
 }
 
 #[derive(Error, Debug)]
 pub enum InterpretError {
-    #[error("Compile error: {0}")]
-    CompileError(#[from] CompileError),
+    #[error("{}", compile_errors_message(.0))]
+    CompileError(Vec<CompileError>),
+
+    #[error("Verify error: {0}")]
+    VerifyError(#[from] VerifyError),
+
+    #[error("[line {line}] Runtime error: {message}")]
+    RuntimeError { line: usize, message: String },
+}
+
+// Not derived with `#[from]`, which would require the errors to be a single `Error` source
+impl From<Vec<CompileError>> for InterpretError {
+    fn from(errors: Vec<CompileError>) -> Self {
+        InterpretError::CompileError(errors)
+    }
+}
 
-    #[error("Runtime error")]
-    RuntimeError
+// One line per error, in the order they were found
+fn compile_errors_message(errors: &[CompileError]) -> String {
+    errors
+        .iter()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 macro_rules! binary_op {
-    ($stack:expr, $op:tt) => {
-        let a = $stack.pop().unwrap();
-        let b = $stack.pop().unwrap();
+    ($self:expr, $op:tt, $int_op:ident) => {
+        binary_op!($self, $op, $int_op, "Operands must be numbers.");
+    };
+    ($self:expr, $op:tt, $int_op:ident, $message:expr) => {
+        // The right operand was pushed last
+        let b = $self.pop()?;
+        let a = $self.pop()?;
+
+        match (a, b) {
+            // Integer results that overflow fall back to floating point
+            (Value::Int(a), Value::Int(b)) => match a.$int_op(b) {
+                Some(result) => $self.push(Value::Int(result))?,
+                None => $self.push(Value::Number(a as f64 $op b as f64))?,
+            },
+            (a, b) => match (a.as_number(), b.as_number()) {
+                (Some(a), Some(b)) => $self.push(Value::Number(a $op b))?,
+                _ => return Err($self.runtime_error($message)),
+            },
+        }
+    };
+}
+
+macro_rules! comparison_op {
+    ($self:expr, $op:tt) => {
+        let b = $self.pop()?;
+        let a = $self.pop()?;
+
+        match (a, b) {
+            (Value::Int(a), Value::Int(b)) => $self.push(Value::Bool(a $op b))?,
+            (a, b) => match (a.as_number(), b.as_number()) {
+                (Some(a), Some(b)) => $self.push(Value::Bool(a $op b))?,
+                _ => return Err($self.runtime_error("Operands must be numbers.")),
+            },
+        }
+    };
+}
+
+#[cfg(feature = "fn-dispatch")]
+type Handler = fn(&mut VM) -> Result<StepResult, InterpretError>;
 
-        $stack.push(a $op b);
+#[cfg(feature = "fn-dispatch")]
+macro_rules! dispatch_table {
+    ($($name:ident => $display:literal),* $(,)?) => {
+        // Handlers indexed by opcode byte, bytes that aren't an opcode report themselves
+        static DISPATCH: [Handler; 256] = {
+            let mut table: [Handler; 256] = [VM::unknown_opcode; 256];
+            $(table[OpCode::$name as usize] = VM::dispatch::<{ OpCode::$name as u8 }>;)*
+            table
+        };
     };
 }
 
-impl<'a, 'outlives_a: 'a> VM<'a> {
-    pub fn new(source: &'outlives_a str) -> Self {
-        const STACK_SIZE: usize = 256;
+#[cfg(feature = "fn-dispatch")]
+crate::chunk::with_opcodes!(dispatch_table);
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    const FRAMES_MAX: usize = 64;
+    const STACK_MAX: usize = Self::FRAMES_MAX * (u8::MAX as usize + 1);
+
+    pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let strings = Rc::new(RefCell::new(Interner::new()));
+
+        let mut vm = Self {
+            frames: Vec::with_capacity(Self::FRAMES_MAX),
+            function: Rc::new(Function::new(None)),
+            ip: 0,
+            stack: Stack::new(Self::STACK_MAX),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            strings,
+            trace: false,
+            verify: false,
+            print_newline: true,
+            result_expression: true,
+            instruction_limit: None,
+            instruction_count: 0,
+            profile: false,
+            #[cfg(feature = "fn-dispatch")]
+            fn_dispatch: false,
+            opcode_counts: HashMap::new(),
+            output,
+            result_line: 0,
+        };
+
+        natives::start_clock();
+        vm.define_native("clock", natives::clock);
+        vm.define_native("assert", natives::assert);
+        vm.define_native("num", natives::num);
+        vm.define_native("str", natives::str);
+
+        vm
+    }
+
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        let name = self.strings.borrow_mut().intern(name);
+        self.globals.insert(name, Value::NativeFn(function));
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    pub fn set_print_newline(&mut self, print_newline: bool) {
+        self.print_newline = print_newline;
+    }
+
+    pub fn set_result_expression(&mut self, result_expression: bool) {
+        self.result_expression = result_expression;
+    }
+
+    pub fn set_instruction_limit(&mut self, instruction_limit: Option<u64>) {
+        self.instruction_limit = instruction_limit;
+    }
+
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile;
+    }
+
+    // Kept next to `match` dispatch so benchmarks can compare the two
+    #[cfg(feature = "fn-dispatch")]
+    pub fn set_fn_dispatch(&mut self, fn_dispatch: bool) {
+        self.fn_dispatch = fn_dispatch;
+    }
+
+    // How many times each opcode executed in the last program, empty unless profiling
+    pub fn profile_report(&self) -> &HashMap<OpCode, u64> {
+        &self.opcode_counts
+    }
+
+    // Frees unreachable objects kept alive by reference cycles, returning how many were freed
+    pub fn collect_garbage(&mut self) -> usize {
+        // Methods called through a bound method don't keep their closure in a stack slot
+        let frame_closures: Vec<Value> = self.frames.iter()
+            .map(|frame| Value::Closure(frame.closure.clone()))
+            .collect();
+
+        let roots = self.stack.iter()
+            .chain(self.globals.values())
+            .chain(frame_closures.iter());
+
+        let freed = self.heap.collect(roots, &self.open_upvalues);
+        self.strings.borrow_mut().remove_unused();
+
+        freed
+    }
+
+    // Number of heap objects that haven't been freed yet
+    pub fn object_count(&self) -> usize {
+        self.heap.object_count()
+    }
+
+    // Sets how many heap objects may be allocated before the next collection
+    pub fn set_gc_threshold(&mut self, threshold: usize) {
+        self.heap.set_threshold(threshold);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn runtime_error(&self, message: &str) -> InterpretError {
+        // The instruction pointer has already moved past the failing instruction's opcode
+        let line = self.function.chunk.get_line(self.ip.saturating_sub(1));
+        eprintln!("[line {}] Error: {}", line, message);
+
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let ip = if depth == 0 { self.ip } else { frame.ip };
+            let line = frame.closure.function.chunk.get_line(ip.saturating_sub(1));
+
+            match &frame.closure.function.name {
+                Some(name) => eprintln!("[line {}] in {}()", line, name),
+                None => eprintln!("[line {}] in script", line),
+            }
+        }
+
+        InterpretError::RuntimeError { line, message: message.to_string() }
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        if self.stack.len() >= self.stack.capacity() {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn peek(&self, distance: usize) -> Result<&Value, InterpretError> {
+        self.stack.len().checked_sub(distance + 1)
+            .map(|index| &self.stack[index])
+            .ok_or_else(|| self.runtime_error("Stack underflow."))
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.function.chunk.code()[self.ip];
+        self.ip += 1;
+
+        byte
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let short = self.function.chunk.read_short(self.ip).unwrap();
+        self.ip += 2;
+
+        short
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let constant_index = self.read_byte();
+        self.function.chunk.get_constant(constant_index as usize).unwrap().clone()
+    }
+
+    fn read_string_constant(&mut self) -> Rc<String> {
+        match self.read_constant() {
+            Value::Str(name) => name,
+            _ => unreachable!("Variable names are always string constants"),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Class(class) => {
+                // The new instance replaces the class in the callee slot, becoming `this` for `init`
+                let callee_slot = self.stack.len() - arg_count - 1;
+                self.stack[callee_slot] = Value::Instance(self.heap.alloc_instance(Instance::new(class.clone())));
+
+                let initializer = class.methods.borrow().get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        Err(self.runtime_error(&message))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Value::BoundMethod(bound_method) => {
+                let callee_slot = self.stack.len() - arg_count - 1;
+                self.stack[callee_slot] = bound_method.receiver.clone();
+
+                self.call(bound_method.method.clone(), arg_count)
+            }
+            Value::NativeFn(native) => {
+                let args_start = self.stack.len() - arg_count;
+                let mut result = native(&self.stack[args_start..])
+                    .map_err(|message| self.runtime_error(&message))?;
+
+                // Strings made by natives are interned like every other string
+                if let Value::Str(string) = &result {
+                    result = Value::Str(self.strings.borrow_mut().intern(string));
+                }
+
+                // Natives don't get a call frame, so discard the callee and arguments right away
+                self.stack.truncate(args_start - 1);
+                self.push(result)
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<(), InterpretError> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+
+        if self.frames.len() == Self::FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        // The caller picks up where it left off once this frame returns
+        if let Some(caller) = self.frames.last_mut() {
+            caller.ip = self.ip;
+        }
+        self.function = closure.function.clone();
+        self.ip = 0;
 
-        Self {
-            compiler: Compiler::new(source),
+        self.frames.push(CallFrame {
+            closure,
             ip: 0,
-            stack: Vec::with_capacity(STACK_SIZE)
+            slots: self.stack.len() - arg_count - 1,
+        });
+
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), InterpretError> {
+        let instance = match self.peek(arg_count)? {
+            Value::Instance(instance) => instance.clone(),
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+
+        // Fields shadow methods, and may hold anything callable
+        let field = instance.fields.borrow().get(name).cloned();
+        if let Some(field) = field {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = field.clone();
+
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(&instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: &Class, name: &str, arg_count: usize) -> Result<(), InterpretError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", name);
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    // Replaces the instance on top of the stack with its method `name`, bound to it
+    fn bind_method(&mut self, class: &Class, name: &str) -> Result<(), InterpretError> {
+        let method = class.methods.borrow().get(name).cloned();
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let message = format!("Undefined property '{}'.", name);
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        let receiver = self.pop()?;
+        let bound_method = self.heap.alloc_bound_method(BoundMethod { receiver, method });
+        self.push(Value::BoundMethod(bound_method))
+    }
+
+    fn capture_upvalue(&mut self, stack_index: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self.open_upvalues.iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(index) if index == stack_index));
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+
+        let upvalue = self.heap.alloc_upvalue(Upvalue::Open(stack_index));
+        self.open_upvalues.push(upvalue.clone());
+
+        upvalue
+    }
+
+    // Moves every variable at or above `last` off the stack and into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(index) if index >= last => {
+                    *upvalue = Upvalue::Closed(stack[index].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    // Checks that `index` is an integer within the list's bounds
+    fn list_index(&self, list: &RefCell<Vec<Value>>, index: &Value) -> Result<usize, InterpretError> {
+        let Value::Int(index) = *index else {
+            return Err(self.runtime_error("List index must be an integer."));
+        };
+
+        usize::try_from(index).ok()
+            .filter(|index| *index < list.borrow().len())
+            .ok_or_else(|| self.runtime_error("List index out of range."))
+    }
+
+    fn pop(&mut self) -> Result<Value, InterpretError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow.")),
         }
     }
 
+    // Runs the source, printing its result unless it's nil
     pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
-        let mut chunk = Chunk::new(None);
-        self.compiler.compile(source, &mut chunk)?;
+        let result = self.interpret_value(source)?;
+        if result != Value::Nil {
+            // The script has already returned, so the error points at its return
+            if let Err(err) = self.write_output(&result, true) {
+                let message = format!("Failed to write output: {}.", err);
+                eprintln!("[line {}] Error: {}", self.result_line, message);
+
+                return Err(InterpretError::RuntimeError { line: self.result_line, message });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the stack and the instruction about to run to the output, between the program's own
+    // output
+    fn write_trace(&mut self) -> anyhow::Result<()> {
+        write!(self.output, "[ ")?;
+        for value in self.stack.iter() {
+            write!(self.output, "{}, ", value)?;
+        }
+        write!(self.output, "] ")?;
+
+        self.function.chunk.write_instruction(&mut self.output, self.ip)?;
+
+        Ok(())
+    }
+
+    // Writes a printed value, flushing right away so output shows up as the program runs
+    fn write_output(&mut self, value: &Value, newline: bool) -> io::Result<()> {
+        if newline {
+            writeln!(self.output, "{}", value)?;
+        } else {
+            write!(self.output, "{}", value)?;
+        }
+
+        self.output.flush()
+    }
+
+    // Runs the source and returns its result: the value of a trailing expression without a
+    // semicolon, or nil
+    pub fn interpret_value(&mut self, source: &str) -> Result<Value, InterpretError> {
+        self.load(source)?;
+        let result = self.run();
+
+        // Globals survive a runtime error, so the VM can keep running other sources
+        if result.is_err() {
+            self.reset();
+        }
+
+        result
+    }
+
+    // Abandons the program being run, keeping globals for the next one
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    // Compiles the source and sets up its call frame, ready to be run one `step` at a time
+    pub fn load(&mut self, source: &str) -> Result<(), InterpretError> {
+        // Compiled functions own their code, so nothing borrows the source once this returns
+        let mut compiler = Compiler::new(source, self.strings.clone());
+        compiler.set_print_newline(self.print_newline);
+        compiler.set_result_expression(self.result_expression);
+        let function = Rc::new(compiler.compile()?);
+        // A previous program may have been left halfway through by `step`
+        self.reset();
+        if self.verify {
+            function.verify()?;
+        }
+        let closure = self.heap.alloc_closure(Closure::new(function));
+        self.instruction_count = 0;
+        self.opcode_counts.clear();
+
+        self.push(Value::Closure(closure.clone()))?;
+        self.call(closure, 0)
+    }
 
+    pub fn stack(&self) -> &[Value] {
+        self.stack.as_slice()
+    }
+
+    // Offset of the next instruction in the current function, or None once the program halted
+    pub fn ip(&self) -> Option<usize> {
+        (!self.frames.is_empty()).then_some(self.ip)
+    }
+
+    fn run(&mut self) -> Result<Value, InterpretError> {
         loop {
-            let instruction_byte = chunk.get_code(self.ip).unwrap();
-            if let Ok(instruction) = OpCode::try_from(*instruction_byte) {
-                #[cfg(debug_assertions)]
-                {
-                    print!("[ ");
-                    for value in self.stack.iter() {
-                        print!("{}, ", value);
-                    }
-                    print!("] ");
+            if let StepResult::Halted(result) = self.step()? {
+                return Ok(result);
+            }
+        }
+    }
+
+    // Executes a single instruction of the loaded program
+    pub fn step(&mut self) -> Result<StepResult, InterpretError> {
+        if self.frames.is_empty() {
+            return Ok(StepResult::Halted(Value::Nil));
+        }
+
+        // Only reachable for hand-written chunks, compiled functions always end with a return
+        if self.ip >= self.function.chunk.len() {
+            return Err(self.runtime_error("Ran off the end of the chunk."));
+        }
+
+        if self.instruction_limit.is_some_and(|limit| self.instruction_count >= limit) {
+            return Err(self.runtime_error("Instruction limit exceeded."));
+        }
+        self.instruction_count += 1;
+
+        // Everything reachable is rooted between instructions
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
 
-                    chunk.disassemble_instruction(self.ip).unwrap();
+        let instruction_byte = self.function.chunk.code()[self.ip];
+
+        // Tracing and profiling need the decoded opcode, so they always go through `execute`
+        #[cfg(feature = "fn-dispatch")]
+        if self.fn_dispatch && !self.trace && !self.profile {
+            return DISPATCH[instruction_byte as usize](self);
+        }
+
+        let Ok(instruction) = OpCode::try_from(instruction_byte) else {
+            return self.unknown_opcode();
+        };
+
+        if self.trace {
+            self.write_trace()
+                .map_err(|err| self.runtime_error(&format!("Failed to write trace: {}.", err)))?;
+        }
+
+        if self.profile {
+            *self.opcode_counts.entry(instruction).or_insert(0) += 1;
+        }
+
+        self.ip += 1;
+        self.execute(instruction)
+    }
+
+    // Runs an instruction whose opcode byte has already been read. Always inlined, so each
+    // dispatch table entry compiles down to its own opcode's arm.
+    #[inline(always)]
+    fn execute(&mut self, instruction: OpCode) -> Result<StepResult, InterpretError> {
+        match instruction {
+            OpCode::Return => {
+                let result = self.pop()?;
+                let frame = self.frames.pop().unwrap();
+                self.close_upvalues(frame.slots);
+
+                // Every statement pops what it pushed, so only the script itself is left under
+                // its result
+                debug_assert!(
+                    !self.frames.is_empty() || self.stack.len() == frame.slots + 1,
+                    "stack not clean after script: {:?}",
+                    self.stack.as_slice(),
+                );
+
+                // Discard the callee along with its arguments and locals
+                self.stack.truncate(frame.slots);
+
+                if self.frames.is_empty() {
+                    self.result_line = self.function.chunk.get_line(self.ip - 1);
+                    return Ok(StepResult::Halted(result));
                 }
 
-                self.ip += 1;
+                let caller = self.frames.last().unwrap();
+                self.function = caller.closure.function.clone();
+                self.ip = caller.ip;
+
+                self.push(result)?;
+            },
+            OpCode::Constant => {
+                let constant_value = self.read_constant();
+                self.push(constant_value)?;
+            }
+            OpCode::ConstantLong => {
+                let constant_index = self.function.chunk.read_constant_long_index(self.ip).unwrap();
+                let constant_value = self.function.chunk.get_constant(constant_index).unwrap().clone();
 
-                match instruction {
-                    OpCode::Return => {
-                        let stack_top = self.stack.pop().unwrap_or(0f32);
-                        println!("{}", stack_top);
+                // OP_CONST_LONG is five bytes long
+                self.ip += 4;
 
-                        return Ok(());
+                self.push(constant_value)?;
+            }
+            OpCode::Constant2 => {
+                let constant_index = self.read_short() as usize;
+                let constant_value = self.function.chunk.get_constant(constant_index).unwrap().clone();
+                self.push(constant_value)?;
+            }
+            OpCode::Negate => {
+                match self.pop()? {
+                    Value::Int(value) => match value.checked_neg() {
+                        Some(negated) => self.push(Value::Int(negated))?,
+                        None => self.push(Value::Number(-(value as f64)))?,
                     },
-                    OpCode::Constant => {
-                        let constant_index = *chunk.get_code(self.ip).unwrap();
-                        let constant_value = *chunk.get_constant(constant_index as usize).unwrap();
+                    Value::Number(value) => self.push(Value::Number(-value))?,
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                }
+            }
+            OpCode::Add => {
+                if let (Value::Str(a), Value::Str(b)) = (self.peek(1)?, self.peek(0)?) {
+                    let concatenated = format!("{}{}", a, b);
+                    let concatenated = self.strings.borrow_mut().intern_owned(concatenated);
 
-                        self.stack.push(constant_value);
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(Value::Str(concatenated))?;
+                } else {
+                    binary_op!(self, +, checked_add, "Operands must be two numbers or two strings.");
+                }
+            },
+            OpCode::Subtract => {
+                binary_op!(self, -, checked_sub);
+            },
+            OpCode::Multiply => {
+                binary_op!(self, *, checked_mul);
+            },
+            OpCode::Divide => {
+                // Dividing by zero is an error rather than producing inf or NaN
+                if let (Some(divisor), Some(_)) = (self.peek(0)?.as_number(), self.peek(1)?.as_number()) {
+                    if divisor == 0.0 {
+                        return Err(self.runtime_error("Division by zero."));
+                    }
+                }
 
-                        // OP_CONST is two bytes long
-                        self.ip += 1;
+                // Dividing two integers truncates
+                binary_op!(self, /, checked_div);
+            },
+            OpCode::Modulo => {
+                if let (Some(divisor), Some(_)) = (self.peek(0)?.as_number(), self.peek(1)?.as_number()) {
+                    if divisor == 0.0 {
+                        return Err(self.runtime_error("Division by zero."));
                     }
-                    OpCode::Negate => {
-                        let stack_top_mut = self.stack.last_mut().unwrap();
-                        *stack_top_mut *= -1f32;
+                }
+
+                // The remainder takes the sign of the dividend
+                binary_op!(self, %, checked_rem);
+            },
+            OpCode::Nil => self.push(Value::Nil)?,
+            OpCode::True => self.push(Value::Bool(true))?,
+            OpCode::False => self.push(Value::Bool(false))?,
+            OpCode::Print | OpCode::PrintNoNewline => {
+                let value = self.pop()?;
+                self.write_output(&value, instruction == OpCode::Print)
+                    .map_err(|err| self.runtime_error(&format!("Failed to write output: {}.", err)))?;
+            }
+            OpCode::DefineGlobal => {
+                let name = self.read_string_constant();
+                let value = self.pop()?;
+
+                self.globals.insert(name, value);
+            }
+            OpCode::GetGlobal => {
+                let name = self.read_string_constant();
+
+                match self.globals.get(&name) {
+                    Some(value) => self.push(value.clone())?,
+                    None => {
+                        let message = format!("Undefined variable '{}'.", name);
+                        return Err(self.runtime_error(&message));
                     }
-                    OpCode::Add => {
-                        binary_op!((&mut self.stack), +);
-                    },
-                    OpCode::Subtract => {
-                        binary_op!((&mut self.stack), -);
-                    },
-                    OpCode::Multiply => {
-                        binary_op!((&mut self.stack), *);
-                    },
-                    OpCode::Divide => {
-                        binary_op!((&mut self.stack), /);
-                    },
-                    _ => {
-                        let compile_err_msg = format!("Unknown instruction byte {}", instruction_byte);
-                        return Err(InterpretError::CompileError(CompileError::CompilationError(compile_err_msg)));
+                }
+            }
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::GetLocal => {
+                let slot = self.frame().slots + self.read_byte() as usize;
+                self.push(self.stack[slot].clone())?;
+            }
+            OpCode::SetLocal => {
+                let slot = self.frame().slots + self.read_byte() as usize;
+                self.stack[slot] = self.peek(0)?.clone();
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_short() as usize;
+                if self.peek(0)?.is_falsey() {
+                    self.ip += offset;
+                }
+            }
+            OpCode::Jump => {
+                let offset = self.read_short() as usize;
+                self.ip += offset;
+            }
+            OpCode::Loop => {
+                let offset = self.read_short() as usize;
+                self.ip -= offset;
+            }
+            OpCode::Call => {
+                let arg_count = self.read_byte() as usize;
+                let callee = self.peek(arg_count)?.clone();
+
+                self.call_value(callee, arg_count)?;
+            }
+            OpCode::Closure => {
+                let function = match self.read_constant() {
+                    Value::Function(function) => function,
+                    _ => unreachable!("Closures are always created from function constants"),
+                };
+                let mut closure = Closure::new(function);
+
+                for _ in 0..closure.function.upvalue_count {
+                    let is_local = self.read_byte() == 1;
+                    let index = self.read_byte() as usize;
+
+                    let upvalue = if is_local {
+                        self.capture_upvalue(self.frame().slots + index)
+                    } else {
+                        self.frame().closure.upvalues[index].clone()
+                    };
+                    closure.upvalues.push(upvalue);
+                }
+
+                let closure = self.heap.alloc_closure(closure);
+                self.push(Value::Closure(closure))?;
+            }
+            OpCode::GetUpvalue => {
+                let slot = self.read_byte() as usize;
+                let upvalue = self.frame().closure.upvalues[slot].clone();
+
+                let value = match &*upvalue.borrow() {
+                    Upvalue::Open(index) => self.stack[*index].clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                };
+                self.push(value)?;
+            }
+            OpCode::SetUpvalue => {
+                let slot = self.read_byte() as usize;
+                let upvalue = self.frame().closure.upvalues[slot].clone();
+                let value = self.peek(0)?.clone();
+
+                match &mut *upvalue.borrow_mut() {
+                    Upvalue::Open(index) => self.stack[*index] = value,
+                    Upvalue::Closed(closed) => *closed = value,
+                };
+            }
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop()?;
+            }
+            OpCode::Class => {
+                let name = self.read_string_constant();
+                let class = self.heap.alloc_class(Class::new(&name));
+                self.push(Value::Class(class))?;
+            }
+            OpCode::BuildList => {
+                let element_count = self.read_byte() as usize;
+                let Some(first) = self.stack.len().checked_sub(element_count) else {
+                    return Err(self.runtime_error("Stack underflow."));
+                };
+
+                let elements = self.stack[first..].to_vec();
+                self.stack.truncate(first);
+
+                let list = self.heap.alloc_list(elements);
+                self.push(Value::List(list))?;
+            }
+            OpCode::GetIndex => {
+                let index = self.pop()?;
+                let list = match self.pop()? {
+                    Value::List(list) => list,
+                    _ => return Err(self.runtime_error("Only lists can be indexed.")),
+                };
+
+                let index = self.list_index(&list, &index)?;
+                let value = list.borrow()[index].clone();
+                self.push(value)?;
+            }
+            OpCode::SetIndex => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let list = match self.pop()? {
+                    Value::List(list) => list,
+                    _ => return Err(self.runtime_error("Only lists can be indexed.")),
+                };
+
+                let index = self.list_index(&list, &index)?;
+                list.borrow_mut()[index] = value.clone();
+
+                // Assignment is an expression, so the value is left on the stack
+                self.push(value)?;
+            }
+            OpCode::GetProperty => {
+                let instance = match self.peek(0)? {
+                    Value::Instance(instance) => instance.clone(),
+                    _ => return Err(self.runtime_error("Only instances have properties.")),
+                };
+                let name = self.read_string_constant();
+
+                let value = instance.fields.borrow().get(name.as_str()).cloned();
+                match value {
+                    Some(value) => {
+                        self.pop()?;
+                        self.push(value)?;
+                    }
+                    None => self.bind_method(&instance.class, &name)?,
+                }
+            }
+            OpCode::SetProperty => {
+                let instance = match self.peek(1)? {
+                    Value::Instance(instance) => instance.clone(),
+                    _ => return Err(self.runtime_error("Only instances have fields.")),
+                };
+                let name = self.read_string_constant();
+
+                let value = self.pop()?;
+                instance.fields.borrow_mut().insert(name.to_string(), value.clone());
+
+                // Discard the instance, leaving the assigned value as the expression's result
+                self.pop()?;
+                self.push(value)?;
+            }
+            OpCode::Method => {
+                let name = self.read_string_constant();
+                let method = match self.pop()? {
+                    Value::Closure(method) => method,
+                    _ => unreachable!("Methods are always closures"),
+                };
+
+                match self.peek(0)? {
+                    Value::Class(class) => class.methods.borrow_mut().insert(name.to_string(), method),
+                    _ => unreachable!("Methods are only defined right after their class"),
+                };
+            }
+            OpCode::Invoke => {
+                let name = self.read_string_constant();
+                let arg_count = self.read_byte() as usize;
+
+                self.invoke(&name, arg_count)?;
+            }
+            OpCode::Inherit => {
+                let superclass = match self.peek(1)? {
+                    Value::Class(superclass) => superclass.clone(),
+                    _ => return Err(self.runtime_error("Superclass must be a class.")),
+                };
+
+                // Methods are copied down, so later lookups never walk the class chain
+                match self.pop()? {
+                    // Only reachable for hand-written chunks, the compiler rejects `class A < A`
+                    Value::Class(subclass) if Rc::ptr_eq(&subclass, &superclass) => {
+                        return Err(self.runtime_error("A class can't inherit from itself."));
                     }
+                    Value::Class(subclass) => {
+                        let methods = superclass.methods.borrow().clone();
+                        subclass.methods.borrow_mut().extend(methods);
+                    }
+                    _ => unreachable!("Only classes inherit"),
                 }
-            } else {
-                let compile_err_msg = format!("Unknown instruction byte {}", instruction_byte);
-                return Err(InterpretError::CompileError(CompileError::CompilationError(compile_err_msg)));
             }
+            OpCode::GetSuper => {
+                let name = self.read_string_constant();
+                let superclass = match self.pop()? {
+                    Value::Class(superclass) => superclass,
+                    _ => unreachable!("`super` always holds a class"),
+                };
+
+                self.bind_method(&superclass, &name)?;
+            }
+            OpCode::SuperInvoke => {
+                let name = self.read_string_constant();
+                let arg_count = self.read_byte() as usize;
+                let superclass = match self.pop()? {
+                    Value::Class(superclass) => superclass,
+                    _ => unreachable!("`super` always holds a class"),
+                };
+
+                self.invoke_from_class(&superclass, &name, arg_count)?;
+            }
+            OpCode::Equal => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+
+                self.push(Value::Bool(a == b))?;
+            }
+            OpCode::Greater => {
+                comparison_op!(self, >);
+            }
+            OpCode::Less => {
+                comparison_op!(self, <);
+            }
+            OpCode::SmallInt => {
+                let value = self.read_byte();
+                self.push(Value::Int(value as i64))?;
+            }
+            OpCode::PopN => {
+                let count = self.read_byte() as usize;
+                if count > self.stack.len() {
+                    return Err(self.runtime_error("Stack underflow."));
+                }
+
+                self.stack.truncate(self.stack.len() - count);
+            }
+            OpCode::Duplicate => {
+                let value = self.peek(0)?.clone();
+                self.push(value)?;
+            }
+            OpCode::Duplicate2 => {
+                let (below, top) = (self.peek(1)?.clone(), self.peek(0)?.clone());
+                self.push(below)?;
+                self.push(top)?;
+            }
+            OpCode::Not => {
+                let value = self.pop()?;
+                self.push(Value::Bool(value.is_falsey()))?;
+            }
+            OpCode::SetGlobal => {
+                let name = self.read_string_constant();
+                let value = self.peek(0)?.clone();
+
+                // Assignment is an expression, so the value is left on the stack
+                match self.globals.get_mut(&name) {
+                    Some(global) => *global = value,
+                    None => {
+                        let message = format!("Undefined variable '{}'.", name);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+            }
+        }
+
+        Ok(StepResult::Continue)
+    }
+
+    // The `DISPATCH` entry for `OP`, which is known to be a valid opcode
+    #[cfg(feature = "fn-dispatch")]
+    fn dispatch<const OP: u8>(&mut self) -> Result<StepResult, InterpretError> {
+        self.ip += 1;
+        self.execute(OpCode::try_from(OP).unwrap())
+    }
+
+    // Only reachable for hand-written chunks, the compiler never emits unknown opcodes
+    fn unknown_opcode(&mut self) -> Result<StepResult, InterpretError> {
+        let instruction_byte = self.function.chunk.code()[self.ip];
+        // Step past the byte so the error points at its line
+        self.ip += 1;
+        Err(self.runtime_error(&format!("Unknown opcode {}.", instruction_byte)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::chunk::Chunk;
+    use crate::object::Function;
+
+    // Output shared with the test after the VM takes ownership of its writer
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Output that can't be written to
+    struct FailingOutput;
+
+    impl Write for FailingOutput {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("output closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn vm() -> VM {
+        VM::with_output(Box::new(io::sink()))
+    }
+
+    fn vm_with_output() -> (VM, SharedOutput) {
+        let output = SharedOutput::default();
+        (VM::with_output(Box::new(output.clone())), output)
+    }
+
+    // Everything `source` prints
+    fn output(source: &str) -> String {
+        let (mut vm, output) = vm_with_output();
+        vm.interpret(source).unwrap();
+
+        output.contents()
+    }
+
+    // The line and message of the runtime error `source` stops with
+    fn runtime_error(source: &str) -> (usize, String) {
+        match vm().interpret(source) {
+            Err(InterpretError::RuntimeError { line, message }) => (line, message),
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+
+    // Runs a hand-built chunk as the script
+    fn run_chunk(vm: &mut VM, chunk: Chunk) -> Result<Value, InterpretError> {
+        let mut function = Function::new(None);
+        function.chunk = chunk;
+        let closure = vm.heap.alloc_closure(Closure::new(Rc::new(function)));
+
+        vm.push(Value::Closure(closure.clone()))?;
+        vm.call(closure, 0)?;
+        vm.run()
+    }
+
+    #[test]
+    fn one_vm_interprets_different_sources() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("var a = 1 + 2; a").unwrap(), Value::Int(3));
+        assert_eq!(vm.interpret_value("a * 10").unwrap(), Value::Int(30));
+    }
+
+    #[test]
+    fn trace_goes_to_the_output_writer() {
+        let (mut vm, output) = vm_with_output();
+        vm.interpret("print 0;").unwrap();
+        assert_eq!(output.contents(), "0\n");
+
+        output.0.borrow_mut().clear();
+        vm.set_trace(true);
+        vm.interpret("print 1;").unwrap();
+
+        assert_eq!(output.contents(), concat!(
+            "[ <script>, ] OP_SMALL_INT 1\n",
+            "[ <script>, 1, ] OP_PRINT\n",
+            "1\n",
+            "[ <script>, ] OP_NIL\n",
+            "[ <script>, nil, ] OP_RETURN\n",
+        ));
+    }
+
+    #[test]
+    fn collection_frees_unreachable_cycles() {
+        let mut vm = vm();
+        vm.interpret("class Node {}").unwrap();
+        vm.collect_garbage();
+        let baseline = vm.object_count();
+
+        vm.interpret("{ var a = Node(); var b = Node(); a.other = b; b.other = a; }").unwrap();
+        assert_eq!(vm.object_count(), baseline + 2);
+
+        assert_eq!(vm.collect_garbage(), 2);
+        assert_eq!(vm.object_count(), baseline);
+    }
+
+    #[test]
+    fn collection_keeps_objects_held_by_the_embedder() {
+        let mut vm = vm();
+        let source = "class Node {} var a = Node(); var b = Node(); a.other = b; b.other = a; a";
+        let value = vm.interpret_value(source).unwrap();
+        vm.interpret("a = nil; b = nil;").unwrap();
+        vm.collect_garbage();
+
+        // The cycle is only reachable through the returned value, and must survive intact
+        let Value::Instance(a) = value else { panic!("expected an instance, got {}", value) };
+        let other = a.fields.borrow().get("other").cloned();
+        let Some(Value::Instance(b)) = other else { panic!("field was cleared") };
+        assert!(matches!(b.fields.borrow().get("other"), Some(Value::Instance(other)) if Rc::ptr_eq(other, &a)));
+    }
+
+    #[test]
+    fn arithmetic_loop_computes_the_same_sum() {
+        let source = "
+            var i = 0;
+            var sum = 0;
+            while (i < 1000) {
+                sum = sum + i * 2 - 1;
+                i = i + 1;
+            }
+            sum
+        ";
+
+        assert_eq!(vm().interpret_value(source).unwrap(), Value::Int(1000 * 1000 - 2 * 1000));
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_the_stack() {
+        assert_eq!(runtime_error("fun f() {\n  f();\n}\nf();"), (2, "Stack overflow.".to_string()));
+    }
+
+    #[test]
+    fn pushing_past_the_stack_limit_is_an_error() {
+        // Pushes nil forever
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Nil as u8, 7);
+        chunk.write_loop(0, 7).unwrap();
+
+        let result = run_chunk(&mut vm(), chunk);
+        assert!(matches!(result, Err(InterpretError::RuntimeError { line: 7, message }) if message == "Stack overflow."));
+    }
+
+    #[test]
+    fn operators_on_an_empty_stack_underflow() {
+        for opcode in [OpCode::Add, OpCode::Negate] {
+            // Pop the script's own closure first, so the operator finds the stack empty
+            let mut chunk = Chunk::new(None);
+            chunk.write(OpCode::Pop as u8, 1);
+            chunk.write(opcode as u8, 1);
+            chunk.write(OpCode::Return as u8, 1);
+
+            let result = run_chunk(&mut vm(), chunk);
+            assert!(matches!(result, Err(InterpretError::RuntimeError { message, .. }) if message == "Stack underflow."));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn strings_concatenate_with_plus() {
+        assert_eq!(output("print \"foo\" + \"bar\";"), "foobar\n");
+    }
+
+    #[test]
+    fn adding_a_string_and_a_number_is_an_error() {
+        assert_eq!(runtime_error("\"x\" + 1;"), (1, "Operands must be two numbers or two strings.".to_string()));
+    }
+
+    #[test]
+    fn globals_can_be_defined_reassigned_and_read() {
+        assert_eq!(output("var a = 1;\nprint a;\na = a + 1;\nprint a;"), "1\n2\n");
+    }
+
+    #[test]
+    fn undefined_globals_are_errors() {
+        assert_eq!(runtime_error("print a;\nvar a = 1;"), (1, "Undefined variable 'a'.".to_string()));
+        assert_eq!(runtime_error("a = 1;"), (1, "Undefined variable 'a'.".to_string()));
+    }
+
+    #[test]
+    fn locals_shadow_outer_variables_in_nested_blocks() {
+        let source = "var a = \"global\";\n{\n  var a = \"outer\";\n  {\n    var a = \"inner\";\n    print a;\n  }\n  print a;\n}\nprint a;";
+
+        assert_eq!(output(source), "inner\nouter\nglobal\n");
+    }
+
+    #[test]
+    fn if_runs_the_taken_branch_only() {
+        assert_eq!(output("if (true) print \"taken\";\nif (false) print \"skipped\";"), "taken\n");
+    }
+
+    #[test]
+    fn if_else_chain_picks_the_first_true_condition() {
+        let source = "fun grade(n) {\n  if (n > 90) return \"a\"; else if (n > 80) return \"b\"; else return \"c\";\n}\n\
+                      print grade(95);\nprint grade(85);\nprint grade(10);";
+
+        assert_eq!(output(source), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn while_loops_until_the_condition_is_false() {
+        assert_eq!(output("var i = 0; while (i < 3) { print i; i = i + 1; }"), "0\n1\n2\n");
+    }
+
+    #[test]
+    fn for_loops_count() {
+        assert_eq!(output("for (var i = 0; i < 3; i = i + 1) print i;"), "0\n1\n2\n");
+    }
+
+    #[test]
+    fn for_loop_exits_when_its_condition_turns_false() {
+        assert_eq!(output("var done = false;\nfor (var i = 0; !done; i = i + 1) { print i; done = i == 1; }"), "0\n1\n");
+    }
+
+    #[test]
+    fn for_loop_without_clauses_runs_until_stopped() {
+        let mut vm = vm();
+        vm.set_instruction_limit(Some(1000));
+
+        assert!(matches!(
+            vm.interpret("var n = 0; for (;;) n = n + 1;"),
+            Err(InterpretError::RuntimeError { message, .. }) if message == "Instruction limit exceeded."
+        ));
+    }
+
+    #[test]
+    fn instruction_limit_stops_an_infinite_loop() {
+        let mut vm = vm();
+        vm.set_instruction_limit(Some(100));
+
+        assert!(matches!(
+            vm.interpret("while (true) {}"),
+            Err(InterpretError::RuntimeError { message, .. }) if message == "Instruction limit exceeded."
+        ));
+    }
+
+    #[test]
+    fn no_instruction_limit_lets_loops_finish() {
+        let mut vm = vm();
+        vm.set_instruction_limit(Some(100));
+        vm.set_instruction_limit(None);
+
+        // Far more than 100 instructions
+        let source = "var i = 0; while (i < 1000) i = i + 1; i";
+        assert_eq!(vm.interpret_value(source).unwrap(), Value::Int(1000));
+    }
+
+    #[test]
+    fn and_or_skip_the_right_operand() {
+        let source = "fun sideEffect() { print \"evaluated\"; return true; }\n\
+                      print false and sideEffect();\nprint true or sideEffect();";
+
+        assert_eq!(output(source), "false\ntrue\n");
+    }
+
+    #[test]
+    fn and_or_evaluate_to_an_operand() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("nil and 1").unwrap(), Value::Nil);
+        assert_eq!(vm.interpret_value("1 and 2").unwrap(), Value::Int(2));
+        assert_eq!(vm.interpret_value("nil or 3").unwrap(), Value::Int(3));
+        assert_eq!(vm.interpret_value("4 or 5").unwrap(), Value::Int(4));
+    }
+
+    #[test]
+    fn functions_take_arguments_and_return_values() {
+        let source = "fun hello() { print \"hello\"; }\nfun add(a, b) { return a + b; }\nhello();\nprint add(1, 2);";
+
+        assert_eq!(output(source), "hello\n3\n");
+    }
+
+    #[test]
+    fn calls_with_the_wrong_argument_count_are_errors() {
+        assert_eq!(runtime_error("fun add(a, b) {}\nadd(1);"), (2, "Expected 2 arguments but got 1.".to_string()));
+    }
+
+    #[test]
+    fn return_unwinds_nested_calls() {
+        let source = "fun inner() { return 1; print \"unreachable\"; }\n\
+                      fun outer() { var a = inner(); return a + 1; }\nprint outer();";
+
+        assert_eq!(output(source), "2\n");
+    }
+
+    #[test]
+    fn clock_never_goes_backwards() {
+        let result = vm().interpret_value("var first = clock(); var second = clock(); second >= first");
+
+        assert_eq!(result.unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn closures_keep_their_captured_variables() {
+        let source = "fun makeCounter() {\n  var count = 0;\n  fun counter() { count = count + 1; return count; }\n  return counter;\n}\n\
+                      var counter = makeCounter();\nprint counter();\nprint counter();\nprint counter();";
+
+        assert_eq!(output(source), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn instance_fields_can_be_set_and_read() {
+        assert_eq!(output("class Point {}\nvar p = Point();\np.x = 1;\nprint p;\nprint p.x;"), "Point instance\n1\n");
+    }
+
+    #[test]
+    fn reading_an_undefined_field_is_an_error() {
+        assert_eq!(runtime_error("class Point {}\nprint Point().x;"), (2, "Undefined property 'x'.".to_string()));
+    }
+
+    #[test]
+    fn methods_read_fields_through_this() {
+        let source = "class Person {\n  init(name) { this.name = name; }\n  greet() { print \"hi \" + this.name; }\n}\n\
+                      Person(\"ada\").greet();";
+
+        assert_eq!(output(source), "hi ada\n");
+    }
+
+    #[test]
+    fn methods_return_computed_values() {
+        let source = "class Rect {\n  init(w, h) { this.w = w; this.h = h; }\n  area() { return this.w * this.h; }\n}\n\
+                      Rect(2, 3).area()";
+
+        assert_eq!(vm().interpret_value(source).unwrap(), Value::Int(6));
+    }
+
+    #[test]
+    fn overriding_methods_can_call_super() {
+        let source = "class A { name() { return \"a\"; } }\n\
+                      class B < A { name() { return \"b\" + super.name(); } }\nprint B().name();";
+
+        assert_eq!(output(source), "ba\n");
+    }
+
+    #[test]
+    fn inheriting_from_a_non_class_is_an_error() {
+        assert_eq!(runtime_error("var x = 1;\nclass B < x {}"), (2, "Superclass must be a class.".to_string()));
+    }
+
+    #[test]
+    fn literals_and_built_strings_are_interned() {
+        let mut vm = vm();
+        let literal = vm.interpret_value("var a = \"ab\"; a").unwrap();
+        let built = vm.interpret_value("\"a\" + \"b\"").unwrap();
+
+        // Equality only compares pointers, so this also checks they were interned together
+        let (Value::Str(literal), Value::Str(built)) = (&literal, &built) else { panic!("expected strings") };
+        assert!(Rc::ptr_eq(literal, built));
+    }
+
+    #[test]
+    fn interpret_value_returns_the_result() {
+        let mut vm = vm();
+
+        // Integer literals stay integers since integer values were added
+        assert_eq!(vm.interpret_value("3 * 4").unwrap(), Value::Int(12));
+        assert_eq!(vm.interpret_value("3.0 * 4").unwrap(), Value::Number(12.0));
+        assert_eq!(vm.interpret_value("print 1;").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn binary_operators_take_operands_in_order() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("5 - 3").unwrap(), Value::Int(2));
+        assert_eq!(vm.interpret_value("6 / 2").unwrap(), Value::Int(3));
+        assert_eq!(vm.interpret_value("var a = 5; var b = 3; a - b").unwrap(), Value::Int(2));
+    }
+
+    #[test]
+    fn dividing_by_zero_is_an_error() {
+        for source in ["print 1 / 0;", "print 0 / 0;", "var zero = 0.0; print 1.5 / zero;", "print 1 % 0;"] {
+            assert_eq!(runtime_error(source), (1, "Division by zero.".to_string()));
+        }
+    }
+
+    #[test]
+    fn conditional_expressions_pick_a_branch() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("true ? 1 : 2").unwrap(), Value::Int(1));
+        assert_eq!(vm.interpret_value("nil ? 1 : 2").unwrap(), Value::Int(2));
+        // Nested conditionals associate to the right
+        assert_eq!(vm.interpret_value("false ? 1 : true ? 3 : 4").unwrap(), Value::Int(3));
+        assert_eq!(vm.interpret_value("true ? false ? 5 : 6 : 7").unwrap(), Value::Int(6));
+    }
+
+    #[test]
+    fn break_leaves_the_loop_early() {
+        assert_eq!(output("var i = 0;\nwhile (true) { if (i == 2) break; print i; i = i + 1; }\nprint \"done\";"), "0\n1\ndone\n");
+    }
+
+    #[test]
+    fn continue_skips_to_the_next_iteration() {
+        let while_loop = "var i = 0;\nwhile (i < 4) { i = i + 1; if (i == 2) continue; print i; }";
+        let for_loop = "for (var i = 1; i <= 4; i = i + 1) { if (i == 3) continue; print i; }";
+
+        assert_eq!(output(while_loop), "1\n3\n4\n");
+        assert_eq!(output(for_loop), "1\n2\n4\n");
+    }
+
+    #[test]
+    fn integers_keep_full_precision() {
+        let mut vm = vm();
+
+        // Above 2^53, where a double can no longer tell neighbouring integers apart
+        assert_eq!(vm.interpret_value("9007199254740993").unwrap(), Value::Int(9_007_199_254_740_993));
+        assert_eq!(vm.interpret_value("9007199254740993 - 1").unwrap(), Value::Int(9_007_199_254_740_992));
+        assert_eq!(vm.interpret_value("9223372036854775807").unwrap(), Value::Int(i64::MAX));
+    }
+
+    #[test]
+    fn mixed_arithmetic_promotes_to_floating_point() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("1 + 0.5").unwrap(), Value::Number(1.5));
+        assert_eq!(vm.interpret_value("10 - 0.25").unwrap(), Value::Number(9.75));
+        assert_eq!(vm.interpret_value("7 / 2").unwrap(), Value::Int(3));
+        assert_eq!(vm.interpret_value("7 / 2.0").unwrap(), Value::Number(3.5));
+        // Integer overflow falls back to floating point
+        assert_eq!(vm.interpret_value("9223372036854775807 * 2").unwrap(), Value::Number(i64::MAX as f64 * 2.0));
+    }
+
+    #[test]
+    fn numbers_keep_double_precision() {
+        let mut vm = vm();
+
+        assert_eq!(vm.interpret_value("3.141592653589793").unwrap(), Value::Number(std::f64::consts::PI));
+        assert_eq!(vm.interpret_value("var pi = 3.141592653589793; pi").unwrap(), Value::Number(std::f64::consts::PI));
+    }
+
+    #[test]
+    fn expression_statements_leave_the_stack_clean() {
+        let mut vm = vm();
+        vm.load("1; 2; 3;").unwrap();
+
+        // Run up to the implicit `return nil` at the end of the script
+        while vm.frame().closure.function.chunk.code()[vm.ip().unwrap()] != OpCode::Nil as u8 {
+            vm.step().unwrap();
+        }
+
+        // Only the script's own closure is left
+        assert_eq!(vm.stack().len(), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stack not clean after script")]
+    fn a_script_leaving_values_on_the_stack_is_caught() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::True as u8, 1);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        _ = run_chunk(&mut vm(), chunk);
+    }
+
+    #[test]
+    fn running_off_the_end_of_a_chunk_is_an_error() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Nil as u8, 1);
+
+        let result = run_chunk(&mut vm(), chunk);
+        assert!(matches!(result, Err(InterpretError::RuntimeError { message, .. }) if message == "Ran off the end of the chunk."));
+    }
+
+    #[test]
+    fn unknown_opcodes_are_runtime_errors() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(u8::MAX, 2);
+
+        let result = run_chunk(&mut vm(), chunk);
+        assert!(matches!(result, Err(InterpretError::RuntimeError { line: 2, message }) if message == "Unknown opcode 255."));
+    }
+
+    #[test]
+    fn duplicate_copies_the_top_of_the_stack() {
+        let mut chunk = Chunk::new(None);
+        chunk.write_constant_op(Value::Int(21), 1).unwrap();
+        chunk.write(OpCode::Duplicate as u8, 1);
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert_eq!(run_chunk(&mut vm(), chunk).unwrap(), Value::Int(42));
+    }
+
+    #[test]
+    fn print_writes_to_the_output_sink() {
+        let (mut vm, output) = vm_with_output();
+        vm.interpret("print \"hello\";").unwrap();
+
+        assert_eq!(output.0.borrow().as_slice(), b"hello\n");
+    }
+
+    #[test]
+    fn runtime_errors_carry_the_source_line() {
+        assert_eq!(runtime_error("var a = 1;\n\nprint -\"x\";"), (3, "Operand must be a number.".to_string()));
+    }
+
+    #[test]
+    fn failing_to_write_the_result_points_at_the_last_line() {
+        let mut vm = VM::with_output(Box::new(FailingOutput));
+
+        assert!(matches!(
+            vm.interpret("var a = 1;\n\na + 2"),
+            Err(InterpretError::RuntimeError { line: 3, message }) if message == "Failed to write output: output closed."
+        ));
+    }
+
+    #[test]
+    fn chained_accesses_run_left_to_right() {
+        let source = "class Box { init(v) { this.field = v; } method() { return Box(this.field + 1); } }\n\
+                      var obj = Box(1);\nobj.method().method().field";
+
+        assert_eq!(vm().interpret_value(source).unwrap(), Value::Int(3));
+    }
+
+    #[test]
+    fn assert_fails_only_on_a_falsey_condition() {
+        assert_eq!(output("assert(1 == 1);"), "");
+        assert_eq!(runtime_error("print 1;\nassert(false);"), (2, "Assertion failed.".to_string()));
+    }
+
+    #[test]
+    fn print_no_newline_leaves_the_line_open() {
+        let (mut vm, output) = vm_with_output();
+        let mut chunk = Chunk::new(None);
+        chunk.write_constant_op(Value::Int(1), 1).unwrap();
+        chunk.write(OpCode::PrintNoNewline as u8, 1);
+        chunk.write_constant_op(Value::Int(2), 1).unwrap();
+        chunk.write(OpCode::Print as u8, 1);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        run_chunk(&mut vm, chunk).unwrap();
+        assert_eq!(output.contents(), "12\n");
+    }
+
+    #[test]
+    fn stepping_shows_each_instruction_on_the_stack() {
+        let mut vm = vm();
+        // A global operand keeps `a + 2` from being folded into one constant
+        vm.interpret("var a = 1;").unwrap();
+        vm.load("a + 2").unwrap();
+
+        let mut stacks = Vec::new();
+        let result = loop {
+            match vm.step().unwrap() {
+                // Everything above the script's own closure
+                StepResult::Continue => stacks.push(vm.stack()[1..].to_vec()),
+                StepResult::Halted(result) => break result,
+            }
+        };
+
+        assert_eq!(stacks, [
+            vec![Value::Int(1)],
+            vec![Value::Int(1), Value::Int(2)],
+            vec![Value::Int(3)],
+        ]);
+        assert_eq!(result, Value::Int(3));
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn variables_without_an_initializer_start_as_nil() {
+        assert_eq!(output("var x;\nprint x;"), "nil\n");
+        assert_eq!(output("{ var x; print x; }"), "nil\n");
+    }
+
+    #[test]
+    fn list_elements_can_be_read_and_written() {
+        assert_eq!(output("var l = [1, \"two\", nil];\nprint l;\nprint l[1];"), "[1, two, nil]\ntwo\n");
+        assert_eq!(output("var l = [1, 2];\nl[0] = 3;\nprint l;"), "[3, 2]\n");
+        assert_eq!(output("print [];"), "[]\n");
+    }
+
+    #[test]
+    fn list_index_out_of_range_is_an_error() {
+        assert_eq!(runtime_error("var l = [1, 2];\nprint l[2];"), (2, "List index out of range.".to_string()));
+        assert_eq!(runtime_error("var l = [1, 2];\nl[-1] = 0;"), (2, "List index out of range.".to_string()));
+    }
+
+    #[test]
+    fn profiling_counts_each_opcode_execution() {
+        let mut vm = vm();
+        vm.set_profile(true);
+        vm.interpret("var i = 0;\nwhile (i < 10) i = i + 1;").unwrap();
+
+        let report = vm.profile_report();
+        assert_eq!(report[&OpCode::Add], 10);
+        assert_eq!(report[&OpCode::Loop], 10);
+        // The condition is checked once more to leave the loop
+        assert_eq!(report[&OpCode::Less], 11);
+    }
+
+    #[test]
+    fn negating_a_boolean_is_an_error() {
+        assert_eq!(runtime_error("print 1;\nprint -true;"), (2, "Operand must be a number.".to_string()));
+    }
+
+    #[test]
+    fn empty_source_runs_without_output() {
+        assert_eq!(output(""), "");
+        assert_eq!(vm().interpret_value("").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn non_ascii_names_can_be_declared_and_used() {
+        assert_eq!(output("var café = 1;\n{ var 变量 = café + 1; print 变量; }"), "2\n");
+    }
+
+    #[test]
+    fn compound_assignment_updates_variables_fields_and_elements() {
+        assert_eq!(output("var x = 1;\nx += 2;\nprint x;"), "3\n");
+        assert_eq!(output("{ var x = 10; x -= 2; x *= 3; print x; }"), "24\n");
+        assert_eq!(output("class A {}\nvar a = A();\na.f = 5;\na.f += 2;\nprint a.f;"), "7\n");
+        assert_eq!(output("var l = [1, 2];\nl[0] += 1;\nl[1] *= 10;\nprint l;"), "[2, 20]\n");
+
+        // The assigned value is the expression's result
+        assert_eq!(vm().interpret_value("var l = [4];\nl[0] %= 3").unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn compound_assignment_to_a_missing_element_is_an_error() {
+        assert_eq!(runtime_error("var l = [1];\nl[1] += 1;"), (2, "List index out of range.".to_string()));
+    }
+
+    #[test]
+    fn num_parses_strings_explicitly() {
+        let mut vm = vm();
+        assert_eq!(vm.interpret_value("num(\"3.5\")").unwrap(), Value::Number(3.5));
+        assert_eq!(vm.interpret_value("num(\"42\") + 1").unwrap(), Value::Int(43));
+
+        assert_eq!(runtime_error("num(\"abc\");"), (1, "Can't convert 'abc' to a number.".to_string()));
+        // Strings are never converted implicitly
+        assert_eq!(runtime_error("\"3\" + 4;"), (1, "Operands must be two numbers or two strings.".to_string()));
+    }
+
+    #[test]
+    fn failing_to_print_is_a_runtime_error() {
+        let mut vm = VM::with_output(Box::new(FailingOutput));
+
+        assert!(matches!(
+            vm.interpret("var a = 1;\nprint a;"),
+            Err(InterpretError::RuntimeError { line: 2, message }) if message == "Failed to write output: output closed."
+        ));
+    }
+
+    #[test]
+    fn calling_a_non_function_is_an_error() {
+        assert_eq!(runtime_error("var x = 1; x();"), (1, "Can only call functions and classes.".to_string()));
+        assert_eq!(runtime_error("var x = 1;\n\"str\"();"), (2, "Can only call functions and classes.".to_string()));
+    }
+
+    #[test]
+    fn print_can_be_compiled_without_a_newline() {
+        let (mut vm, output) = vm_with_output();
+        vm.set_print_newline(false);
+        vm.interpret("print 1;\nprint \"a\";").unwrap();
+        assert_eq!(output.contents(), "1a");
+
+        // A script's result is still echoed on its own line
+        vm.interpret("print 2; 3").unwrap();
+        assert_eq!(output.contents(), "1a23\n");
+
+        vm.set_print_newline(true);
+        vm.interpret("print 4;").unwrap();
+        assert_eq!(output.contents(), "1a23\n4\n");
+    }
+
+    #[cfg(feature = "fn-dispatch")]
+    #[test]
+    fn fn_dispatch_runs_programs_like_match() {
+        let source = "
+class A { init(n) { this.n = n; } get() { return this.n; } }
+class B < A { get() { return super.get() * 2; } }
+fun counter() { var i = 0; fun next() { i = i + 1; return i; } return next; }
+var next = counter();
+next();
+var list = [1, 2.5, \"x\"];
+list[0] = list[0] + 300;
+for (var i = 0; i < 3; i = i + 1) print list[i];
+print B(21).get() % 5;
+print next() == 2 and !nil;
+print -(7 / 2) - 1;
+";
+        let run = |fn_dispatch| {
+            let output = SharedOutput::default();
+            let mut vm = VM::with_output(Box::new(output.clone()));
+            vm.set_fn_dispatch(fn_dispatch);
+            vm.interpret(source).unwrap();
+            output.contents()
+        };
+
+        assert_eq!(run(true), run(false));
+    }
+
+    #[cfg(feature = "fn-dispatch")]
+    #[test]
+    fn fn_dispatch_reports_unknown_opcodes() {
+        let mut chunk = Chunk::new(None);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(u8::MAX, 2);
+        let mut vm = vm();
+        vm.set_fn_dispatch(true);
+
+        let result = run_chunk(&mut vm, chunk);
+        assert!(matches!(result, Err(InterpretError::RuntimeError { line: 2, message }) if message == "Unknown opcode 255."));
+    }
+}
@@ -0,0 +1,139 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox")).args(args).output().unwrap()
+}
+
+// Runs `source` as a script file named `name`
+fn run_script(name: &str, source: &str) -> Output {
+    let path: PathBuf = [env!("CARGO_TARGET_TMPDIR"), name].iter().collect();
+    fs::write(&path, source).unwrap();
+
+    run(&[path.to_str().unwrap()])
+}
+
+// Runs the REPL with `input` typed in, then closes stdin
+fn run_repl(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn repl_reads_triple_quoted_strings_containing_quotes() {
+    let output = run_repl("print \"\"\"it\"s\"\"\";\nprint 2;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(stdout, "> it\"s\n> 2\n> ");
+}
+
+#[test]
+fn repl_waits_for_a_multi_line_statement() {
+    let output = run_repl("if (true) {\nprint 1;\n}\nprint 2;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(stdout, "> ... ... 1\n> 2\n> ");
+}
+
+#[test]
+fn repl_echoes_the_value_of_an_expression() {
+    let output = run_repl("2 * 21\nvar a = 1;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    // Statements don't echo anything
+    assert_eq!(stdout, "> 42\n> > ");
+}
+
+#[test]
+fn version_prints_the_crate_version() {
+    let output = run(&["--version"]);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("rlox {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn help_prints_usage() {
+    let output = run(&["--help"]);
+
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("Usage: rlox"));
+}
+
+#[test]
+fn unknown_flag_is_a_usage_error() {
+    let output = run(&["--bogus"]);
+
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("Usage: rlox"));
+}
+
+#[test]
+fn script_errors_set_the_exit_code() {
+    assert_eq!(run_script("ok.lox", "print 1;").status.code(), Some(0));
+    assert_eq!(run_script("compile_error.lox", "print ;").status.code(), Some(65));
+    assert_eq!(run_script("runtime_error.lox", "print -nil;").status.code(), Some(70));
+}
+
+#[test]
+fn compile_errors_are_reported_once_with_a_snippet() {
+    let output = run_script("compile_error_report.lox", "print ;\n");
+
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), concat!(
+        "Failed to compile: [line 1] Error at ';': Expect expression.\n",
+        "   1 | print ;\n",
+        "     |       ^\n",
+    ));
+}
+
+#[test]
+fn scripts_need_a_semicolon_after_their_last_expression() {
+    let output = run_script("missing_semicolon.lox", "1 + 2");
+
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr).unwrap()
+        .starts_with("Failed to compile: [line 1] Error at end: Expect ';' after expression.\n"));
+}
+
+#[test]
+fn runtime_errors_report_the_line_and_call_stack() {
+    let source = "fun inner() {\n  return -nil;\n}\nfun outer() {\n  inner();\n}\nouter();\n";
+    let output = run_script("stack_trace.lox", source);
+
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), concat!(
+        "[line 2] Error: Operand must be a number.\n",
+        "[line 2] in inner()\n",
+        "[line 5] in outer()\n",
+        "[line 7] in script\n",
+    ));
+}
+
+#[test]
+fn dump_bytecode_prints_the_disassembly() {
+    let path: PathBuf = [env!("CARGO_TARGET_TMPDIR"), "dump.lox"].iter().collect();
+    fs::write(&path, "print 1;").unwrap();
+    let output = run(&["--dump-bytecode", path.to_str().unwrap()]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success());
+    assert!(stdout.contains("OP_PRINT"));
+    assert!(stdout.contains("OP_RETURN"));
+}
+
+#[test]
+fn desugared_comparisons_keep_their_operand_order() {
+    let output = run_script("comparisons.lox", "print 5 >= 5;\nprint 5 > 5;\nprint 2 <= 3;\nprint 3 <= 2;\nprint 4 >= 5;\n");
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "true\nfalse\ntrue\nfalse\nfalse\n");
+}